@@ -0,0 +1,304 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use failure::Error;
+
+use serde::Deserialize;
+
+use directories::ProjectDirs;
+
+use crate::output::Format;
+
+/// Settings read from the configuration file. Every setting is optional, and the command line
+/// arguments take precedence over the values given here.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Where the measurements are uploaded to.
+    pub url: Option<String>,
+    /// Format of the uploaded and printed measurements.
+    pub format: Option<Format>,
+    /// File the measurements are appended to.
+    pub output: Option<String>,
+    /// Scan timeout, seconds.
+    pub timeout: Option<u64>,
+    /// Daemon mode publishing interval, seconds.
+    pub interval: Option<u64>,
+    /// Address of the Prometheus metrics endpoint in daemon mode.
+    pub metrics: Option<String>,
+    /// Name or address of the Bluetooth adapter.
+    pub adapter: Option<String>,
+    /// Whether the adapter is reset before scanning.
+    pub reset: Option<bool>,
+    /// Whether measurements are collected from every RuuviTag.
+    pub discover: Option<bool>,
+    #[serde(default)]
+    pub upload: UploadConfig,
+    #[serde(default)]
+    pub mqtt: MqttConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub sensors: Vec<SensorConfig>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UploadConfig {
+    /// Token sent as a bearer token in the Authorization header.
+    pub bearer_token: Option<Secret>,
+    /// User name of basic authentication.
+    pub username: Option<String>,
+    /// Password of basic authentication.
+    pub password: Option<Secret>,
+    /// Extra headers sent with every upload.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    pub signing: Option<SigningConfig>,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// How long to wait for a connection to the server, seconds.
+    pub connect_timeout: Option<u64>,
+    /// How long to wait for the server to respond to an upload, seconds.
+    pub request_timeout: Option<u64>,
+    /// How many times a failed upload is retried before the measurements are cached.
+    pub retries: Option<u32>,
+    /// Delay before the first retry, seconds. The delay is doubled for each retry.
+    pub retry_delay: Option<u64>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// CA certificates trusted in addition to the system certificates, PEM.
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate, optionally followed by its chain, PEM.
+    pub cert_file: Option<PathBuf>,
+    /// Private key of the client certificate, PEM. By default read from the certificate file.
+    pub key_file: Option<PathBuf>,
+    /// Whether invalid server certificates are accepted. Only meant for testing.
+    pub danger_accept_invalid_certs: Option<bool>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Key of the HMAC-SHA256 signature.
+    pub key: Secret,
+    /// Header the signature is sent in.
+    pub signature_header: Option<String>,
+    /// Header the Unix timestamp included in the signature is sent in.
+    pub timestamp_header: Option<String>,
+}
+
+/// A secret that is read from an environment variable or a file, so that it does not have to
+/// be written in the configuration file or given on the command line.
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Secret {
+    Env(String),
+    File(PathBuf),
+}
+
+impl Secret {
+    /// Reads the secret. Trailing newlines are removed from secrets read from a file.
+    pub fn read(&self) -> Result<String, Error> {
+        match self {
+            Secret::Env(name) => env::var(name).map_err(|error| {
+                failure::format_err!("failed to read environment variable {}: {}", name, error)
+            }),
+            Secret::File(path) => fs::read_to_string(path)
+                .map(|secret| secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+                .map_err(|error| {
+                    failure::format_err!("failed to read {}: {}", path.display(), error)
+                }),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// The broker the measurements are published to instead of uploading them.
+    pub url: Option<String>,
+    /// Topic template, `{alias}` and `{address}` are replaced with those of the sensor.
+    pub topic: Option<String>,
+    pub qos: Option<u8>,
+    pub retain: Option<bool>,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    /// CA certificate used to verify the broker when using TLS.
+    pub ca_file: Option<PathBuf>,
+    /// Whether Home Assistant discovery messages are published.
+    pub discovery: Option<bool>,
+    /// Topic prefix of the Home Assistant discovery messages.
+    pub discovery_prefix: Option<String>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Whether measurements that could not be uploaded are cached.
+    pub enabled: Option<bool>,
+    /// Directory the measurements are cached in.
+    pub dir: Option<PathBuf>,
+    /// Maximum number of cached sets sent at once.
+    pub batch_size: Option<usize>,
+    /// Maximum total size of the cached files sent at once, bytes.
+    pub batch_bytes: Option<u64>,
+    /// Maximum number of cached sets kept.
+    pub max_entries: Option<usize>,
+    /// Maximum total size of the cached files kept, bytes.
+    pub max_bytes: Option<u64>,
+    /// Cached sets older than this are discarded, seconds.
+    pub max_age: Option<u64>,
+    /// How cached sets are discarded when the cache is over its limits.
+    pub eviction: Option<Eviction>,
+    /// Interval that the cache is thinned out to when evicting by thinning, seconds.
+    pub thin_interval: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Eviction {
+    /// The oldest sets are discarded.
+    #[default]
+    Oldest,
+    /// The cache is thinned out to one set per interval before discarding the oldest sets.
+    Thin,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensorConfig {
+    pub address: String,
+    pub alias: Option<String>,
+    /// Arbitrary key-value pairs attached to every measurement of the sensor.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Reads the configuration from the given file. If no file is given, the configuration is
+    /// read from the default location, if it exists there.
+    pub fn load(path: Option<&Path>) -> Result<Config, Error> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref error) if !required && error.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(error) => {
+                return Err(failure::format_err!(
+                    "failed to read {}: {}",
+                    path.display(),
+                    error
+                ));
+            }
+        };
+
+        Config::parse(&contents)
+            .map_err(|error| failure::format_err!("failed to parse {}: {}", path.display(), error))
+    }
+
+    pub fn parse(contents: &str) -> Result<Config, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+/// Returns the location of the configuration file used when none is given explicitly.
+pub fn default_path() -> Option<PathBuf> {
+    ProjectDirs::from("dev", "otimperi", "ruuvitag-upload")
+        .map(|dirs| dirs.config_dir().join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            url = "http://localhost:8080/measurements"
+            format = "influx"
+            timeout = 30
+            reset = false
+
+            [mqtt]
+            url = "mqtt://localhost"
+            qos = 1
+            password = { env = "MQTT_PASSWORD" }
+
+            [cache]
+            enabled = false
+            dir = "/var/cache/ruuvitag-upload"
+            max_entries = 1000
+            eviction = "thin"
+
+            [[sensors]]
+            address = "AA:AA:AA:AA:AA:AA"
+            alias = "kitchen"
+            metadata = { floor = "1" }
+
+            [[sensors]]
+            address = "BB:BB:BB:BB:BB:BB"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.url.as_deref(),
+            Some("http://localhost:8080/measurements")
+        );
+        assert_eq!(config.format, Some(Format::Influx));
+        assert_eq!(config.timeout, Some(30));
+        assert_eq!(config.interval, None);
+        assert_eq!(config.reset, Some(false));
+        assert_eq!(config.mqtt.url.as_deref(), Some("mqtt://localhost"));
+        assert_eq!(config.mqtt.qos, Some(1));
+        assert_eq!(config.mqtt.topic, None);
+        assert!(
+            matches!(config.mqtt.password, Some(Secret::Env(ref name)) if name == "MQTT_PASSWORD")
+        );
+        assert_eq!(config.cache.enabled, Some(false));
+        assert_eq!(
+            config.cache.dir,
+            Some(PathBuf::from("/var/cache/ruuvitag-upload"))
+        );
+        assert_eq!(config.cache.max_entries, Some(1000));
+        assert_eq!(config.cache.eviction, Some(Eviction::Thin));
+        assert_eq!(config.sensors.len(), 2);
+        assert_eq!(config.sensors[0].alias.as_deref(), Some("kitchen"));
+        assert_eq!(config.sensors[0].metadata["floor"], "1");
+        assert_eq!(config.sensors[1].alias, None);
+        assert!(config.sensors[1].metadata.is_empty());
+
+        assert!(Config::parse("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        let path = test_dir.path().join("token");
+        fs::write(&path, "secret\n").unwrap();
+
+        assert_eq!(Secret::File(path).read().unwrap(), "secret");
+        assert!(Secret::File(test_dir.path().join("missing"))
+            .read()
+            .is_err());
+        assert!(Secret::Env("RUUVITAG_UPLOAD_TEST_MISSING".to_string())
+            .read()
+            .is_err());
+    }
+}
@@ -1,29 +1,40 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::ffi::OsString;
 use std::fs;
 use std::io::{self, BufReader, Write};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
 use std::process;
-use std::sync::{mpsc::channel, Arc};
-use std::time::{SystemTime, UNIX_EPOCH};
-
-use rumble;
-use rumble::api::{BDAddr, Central, CentralEvent, Peripheral};
-use rumble::bluez::adapter::ConnectedAdapter;
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use failure::Error;
 
-use ruuvi_sensor_protocol::{ParseError, SensorValues};
+use ruuvi_sensor_protocol::{
+    Acceleration, AccelerationVector, BatteryPotential, Humidity, MeasurementSequenceNumber,
+    MovementCounter, ParseError, Pressure, SensorValues, Temperature, TransmitterPower,
+};
 
 use serde::{Deserialize, Serialize};
-use serde_json;
-
-use docopt;
-
-use reqwest;
 
 use directories::ProjectDirs;
 
-#[derive(Serialize, Deserialize)]
+mod config;
+mod metrics;
+mod mqtt;
+mod output;
+mod scanner;
+mod upload;
+
+use crate::config::{Config, Eviction, SensorConfig};
+use crate::metrics::MetricsServer;
+use crate::mqtt::MqttPublisher;
+use crate::output::Format;
+use crate::scanner::{Advertisement, BluezScanner, ReplayScanner, Scanner};
+use crate::upload::Uploader;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct Measurement {
     address: String,
     // Unix timestamp.
@@ -36,24 +47,75 @@ struct Measurement {
     pressure: Option<f64>,
     // Battery potential, volts.
     battery_potential: Option<f64>,
+    // Acceleration along each axis, g.
+    acceleration_x: Option<f64>,
+    acceleration_y: Option<f64>,
+    acceleration_z: Option<f64>,
+    // Transmitter power, dBm.
+    tx_power: Option<i8>,
+    // Number of movements detected by the accelerometer.
+    movement_counter: Option<u32>,
+    // Sequence number of the measurement, incremented for each new measurement.
+    measurement_sequence_number: Option<u32>,
+    // Received signal strength, dBm.
+    rssi: Option<i8>,
+    // Ruuvi data format of the advertisement.
+    data_format: Option<u8>,
+    // Metadata of the sensor from the configuration file.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<String, String>,
 }
 
 impl Measurement {
-    fn new(address: BDAddr, values: SensorValues) -> Measurement {
+    fn new(advertisement: &Advertisement, values: SensorValues) -> Measurement {
+        let acceleration = values.acceleration_vector_as_milli_g();
         Measurement {
-            address: format!("{}", address),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs(),
-            humidity: values.humidity.map(|x| f64::from(x) / 10000.0),
-            temperature: values.temperature.map(|x| f64::from(x) / 1000.0),
-            pressure: values.pressure.map(|x| f64::from(x) / 1000.0),
-            battery_potential: values.battery_potential.map(|x| f64::from(x) / 1000.0),
+            address: advertisement.address.clone(),
+            timestamp: advertisement.timestamp,
+            humidity: values.humidity_as_ppm().map(|x| f64::from(x) / 10000.0),
+            temperature: values
+                .temperature_as_millicelsius()
+                .map(|x| f64::from(x) / 1000.0),
+            pressure: values.pressure_as_pascals().map(|x| f64::from(x) / 1000.0),
+            battery_potential: values
+                .battery_potential_as_millivolts()
+                .map(|x| f64::from(x) / 1000.0),
+            acceleration_x: acceleration.map(|AccelerationVector(x, _, _)| f64::from(x) / 1000.0),
+            acceleration_y: acceleration.map(|AccelerationVector(_, y, _)| f64::from(y) / 1000.0),
+            acceleration_z: acceleration.map(|AccelerationVector(_, _, z)| f64::from(z) / 1000.0),
+            tx_power: values.tx_power_as_dbm().or(advertisement.tx_power_level),
+            movement_counter: values.movement_counter(),
+            measurement_sequence_number: values.measurement_sequence_number(),
+            rssi: advertisement.rssi,
+            // The format is the first byte after the two byte manufacturer id.
+            data_format: advertisement.manufacturer_data.get(2).cloned(),
+            metadata: BTreeMap::new(),
         }
     }
 }
 
+/// Returns a set with a measurement of one sensor, `kitchen`, for tests.
+#[cfg(test)]
+fn kitchen_measurements() -> HashMap<String, Measurement> {
+    let mut measurements = HashMap::new();
+    measurements.insert(
+        "kitchen".to_string(),
+        Measurement {
+            address: "AA:AA:AA:AA:AA:AA".to_string(),
+            timestamp: 1554300000,
+            temperature: Some(21.5),
+            ..Default::default()
+        },
+    );
+    measurements
+}
+
+/// A sensor to collect measurements from.
+struct Sensor {
+    alias: String,
+    metadata: BTreeMap<String, String>,
+}
+
 const USAGE: &str = "
 ruuvitag-upload
 
@@ -70,13 +132,113 @@ structure
             \"humidity\": <0-100%>,
             \"pressure\": <kPa>,
             \"temperature\": <Celcius>,
-            \"battery_potential\": <volts>
+            \"battery_potential\": <volts>,
+            \"acceleration_x\": <g>,
+            \"acceleration_y\": <g>,
+            \"acceleration_z\": <g>,
+            \"tx_power\": <dBm>,
+            \"movement_counter\": <count>,
+            \"measurement_sequence_number\": <number>,
+            \"rssi\": <dBm>,
+            \"data_format\": <number>,
+            \"metadata\": { \"<KEY>\": \"<VALUE>\", ... }
         },
         ...
     }
 
 where ALIAS will either be the address of the sensor, or
-an alias that you can define.
+an alias that you can define. Values that the sensor does
+not broadcast are null. Acceleration is available from data
+formats 3 (RAWv1) and 5 (RAWv2). Transmitter power, movement
+counter and measurement sequence number are only available
+from sensors using data format 5. For other formats the
+transmitter power is taken from the TX power level of the
+advertisement, if present.
+
+Live scans don't support the RSSI: the Bluetooth library in
+use (rumble 0.3) drops the RSSI of the advertisements it
+receives, so measurements from a live scan always have a null
+RSSI. Only replayed advertisements that include it have an
+RSSI. The metadata is only present if it is defined for the
+sensor in the configuration file.
+
+Alternatively the measurements can be formatted as InfluxDB
+line protocol, one line per sensor:
+
+    ruuvitag,alias=<ALIAS>,address=XX:XX:XX:XX:XX:XX[,<KEY>=<VALUE>...] humidity=<0-100%>,... <timestamp>
+
+The alias, the address and the metadata of the sensor are
+written as tags and the values as fields. Values that the
+sensor does not broadcast are left out. The timestamps are in
+seconds, so when uploading, precision=s is added to the URL
+unless it already contains a precision. This works with both
+the InfluxDB 1.x (/write?db=...) and 2.x
+(/api/v2/write?org=...&bucket=...) write endpoints.
+
+The measurements can also be formatted as CSV. The first row
+is a header, followed by one row per sensor. The columns are
+always in the same order: alias, address and the values in
+the order of the JSON structure above. The metadata is written
+to a single column as KEY=VALUE pairs separated by semicolons.
+
+If no URL is given, the measurements can be appended to a
+file instead of writing them to stdout. In CSV format the
+header row is only written if the file is empty.
+
+Uploads can be authenticated with a bearer token or with
+basic authentication, and extra headers can be sent with
+every upload. The token and the password are read from an
+environment variable or from a file, never from the command
+line or the configuration file itself. The same settings are
+used when uploading cached measurements.
+
+Uploads can also be signed, so that the receiver can verify
+where they came from. The signature is the HMAC-SHA256 of the
+Unix timestamp of the upload, a period and the exact body,
+as hex. It is sent in the X-Signature header and the
+timestamp in the X-Signature-Timestamp header, unless other
+headers are configured. Cached measurements are signed when
+they are uploaded.
+
+For HTTPS uploads, additional CA certificates can be trusted
+and a client certificate can be given for mutual TLS. The
+certificates and the key are PEM files. If the key is not
+given, it is read from the certificate file. Verifying the
+certificate of the server can be turned off for testing with
+danger_accept_invalid_certs.
+
+An upload gives up if connecting takes longer than 10 seconds
+or the server doesn't respond in 30 seconds. Uploads that fail
+because of a connection error, a timeout, a server error (5xx)
+or 429 Too Many Requests are retried 3 times before the
+measurements are cached. The delay before a retry starts at 1
+second and doubles for each retry, up to a minute, and is
+randomized a bit. A Retry-After given by the server in seconds
+is used as the delay instead, up to 5 minutes. If the server
+asks to wait longer, the measurements are cached right away.
+The timeouts, the number of retries and the initial delay can
+be changed in the configuration file.
+
+Instead of uploading them, the measurements can be published
+to an MQTT broker. Each measurement is published as a JSON
+object (the value of an alias in the structure above) to a
+topic of its own, ruuvitag/<ALIAS> by default. In the topic
+template {alias} and {address} are replaced with those of the
+sensor. The QoS, the retain flag, the credentials and the CA
+certificate of the broker are set in the configuration file.
+Like the upload password, the broker password is read from an
+environment variable or from a file.
+If the broker can't be reached, the measurements are cached
+just like when uploading fails.
+
+With Home Assistant discovery enabled, each sensor appears in
+Home Assistant as a device with an entity for temperature,
+humidity, pressure and battery potential, as far as the
+values are known. Since live scans don't support the RSSI,
+there is only an RSSI entity for replayed advertisements that
+include it. The retained discovery messages are published to
+homeassistant/sensor/ruuvitag_<ADDRESS>/<QUANTITY>/config
+before the first measurement of the sensor.
 
 If uploading measurements fails, the measurements are
 cached. The cached measurements are uploaded the next time
@@ -85,14 +247,180 @@ first, from oldest to newest. If uploading cached measurements
 fails, the current measurements are again cached for next time.
 This way, you won't lose any measurements. When a cached
 measurement is succesfully uploaded, the cache entry will be
-removed.
+removed. A cache entry that can't be read is moved to the
+corrupt subdirectory of the cache, so that it doesn't hold up
+the entries after it. If the cache directory isn't writable,
+ruuvitag-upload fails right away instead of losing the
+measurements later.
+
+Cached measurements can also be sent in batches, limited by
+the number of cached sets and the total size of their cache
+files. When uploading, a batch is sent in a single request:
+in JSON format as an array of the sets, otherwise as the
+records of every set after a single header. When publishing
+to an MQTT broker, a batch is published using one connection.
+Only the cache entries of a batch that was sent successfully
+are removed.
+
+The cache can be limited by the number of cached sets, the
+total size of their cache files and their age. The limits are
+applied whenever measurements are cached. Sets older than the
+maximum age are discarded. If the cache is still over its
+limits, the oldest sets are discarded, or with thinning the
+cache is first thinned out to one set per interval (one hour
+by default). Corrupt cache entries count towards the maximum
+size and age too, but they only get the space that the
+cached measurements leave over. Temporary files left behind
+by an interrupted write are removed after five minutes. A
+warning is printed whenever cached measurements are
+discarded.
+
+If a timeout is given, scanning stops when the timeout expires
+even if some of the sensors have not been seen. The measurements
+that were collected are processed as usual, and the missing
+sensors are reported on stderr.
+
+If an interval is given, ruuvitag-upload runs as a daemon
+and keeps scanning. Once every interval it takes the latest
+measurement of each sensor seen during the interval and
+uploads them (or writes them to stdout, one set per line)
+just like a single run would, including caching and
+uploading of cached measurements.
+
+In daemon mode the latest measurements can also be served
+for Prometheus at http://ADDR/metrics. The following gauges
+are exported for each sensor, labelled by alias and address:
+
+    ruuvitag_temperature_celsius
+    ruuvitag_humidity_percent
+    ruuvitag_pressure_pascals
+    ruuvitag_battery_volts
+    ruuvitag_last_seen_timestamp_seconds
+
+A sensor that disappears keeps its latest values, but its
+last seen timestamp shows how old they are.
+
+In discovery mode, measurements are collected from every
+RuuviTag seen during the timeout (10 seconds by default), not
+just from the given sensors. RuuviTags that are not among
+the configured sensors use their address as the alias.
+
+The scan command listens for RuuviTags like discovery mode
+and prints a table of the tags that were found, including
+their latest values. The table is followed by a line of
+sensor arguments (XX:XX:XX:XX:XX:XX=alias) that can be
+pasted to the command line.
+
+The cache commands work on the cached measurements without
+scanning. list prints a table of the cache entries with the
+time each was cached, the number of sensors in it and its
+size. show prints an entry in the chosen format. flush sends
+the cached measurements to the URL or the MQTT broker, like
+a regular run would before sending new measurements. purge
+removes the entries older than the given age. export prints
+all cached measurements as one document: in JSON format as
+an array of the sets, otherwise as the records of every set
+after a single header. With an output file, export appends
+the cached sets to it like regular runs would.
+
+Instead of scanning with Bluetooth, advertisements can be
+replayed from a file or from stdin. Each line of the input
+contains the sensor address, the manufacturer specific data
+as hex (including the manufacturer id) and optionally a unix
+timestamp and an RSSI in dBm, separated by whitespace:
+
+    XX:XX:XX:XX:XX:XX 99040317014535...86 [<timestamp>] [<rssi>]
+
+Empty lines and lines starting with # are ignored. Lines
+without a timestamp get the current time. The whole input is
+read, and the measurements are published as one set per
+timestamp, from oldest to newest, so that a capture taken over
+hours is published reading by reading. If an interval is
+given, the sets are formed per interval of the timestamps
+instead. Within a set, the latest measurement of each sensor
+is used. A sensor missing from every set is reported as
+missing. If uploading or publishing a set fails, the
+remaining sets are cached without trying to send them. The
+metrics endpoint is not available when replaying.
 
 Parts of the program are inspired by and some parts are copied
 from ruuvitag-listener (https://github.com/lautis/ruuvitag-listener).
 
+CONFIGURATION:
+
+The sensors and most of the options can also be given in a
+TOML configuration file. Options given on the command line
+take precedence over the ones in the configuration file.
+
+    url = \"https://example.com/measurements\"
+    format = \"influx\"             # --format
+    output = \"measurements.csv\"   # --output
+    timeout = 60                  # --timeout
+    interval = 300                # --interval
+    metrics = \"0.0.0.0:9521\"      # --metrics
+    adapter = \"hci0\"              # --adapter
+    reset = false                 # --no-reset
+    discover = true               # --discover
+
+    [upload]
+    connect_timeout = 10
+    request_timeout = 30
+    retries = 3
+    retry_delay = 1
+    bearer_token = { env = \"RUUVITAG_TOKEN\" }
+    # username = \"gateway\"
+    # password = { file = \"/etc/ruuvitag-upload/password\" }
+
+    [upload.headers]
+    X-Gateway = \"attic\"
+
+    [upload.signing]
+    key = { env = \"RUUVITAG_SIGNING_KEY\" }
+    signature_header = \"X-Signature\"
+    timestamp_header = \"X-Signature-Timestamp\"
+
+    [upload.tls]
+    ca_file = \"/etc/ruuvitag-upload/ca.pem\"
+    cert_file = \"/etc/ruuvitag-upload/client.pem\"
+    key_file = \"/etc/ruuvitag-upload/client.key\"
+    danger_accept_invalid_certs = false
+
+    [mqtt]
+    url = \"mqtts://example.com\"   # --mqtt
+    topic = \"home/{alias}\"
+    qos = 1
+    retain = true
+    username = \"ruuvitag\"
+    password = { env = \"MQTT_PASSWORD\" }
+    ca_file = \"/etc/ssl/broker.pem\"
+    discovery = true
+    discovery_prefix = \"homeassistant\"
+
+    [cache]
+    enabled = false               # --no-cache
+    dir = \"/var/cache/ruuvitag-upload\" # --cache-dir
+    batch_size = 100
+    batch_bytes = 1000000
+    max_entries = 10000
+    max_bytes = 100000000
+    max_age = 604800              # seconds
+    eviction = \"thin\"              # or \"oldest\"
+    thin_interval = 3600          # seconds
+
+    [[sensors]]
+    address = \"XX:XX:XX:XX:XX:XX\"
+    alias = \"mysensor\"
+    metadata = { location = \"kitchen\" }
+
 USAGE:
 
-    ruuvitag-upload [--url=URL] <sensor>...
+    ruuvitag-upload scan [options]
+    ruuvitag-upload cache list [options]
+    ruuvitag-upload cache show [options] <entry>
+    ruuvitag-upload cache flush [options]
+    ruuvitag-upload cache purge [options]
+    ruuvitag-upload cache export [options]
+    ruuvitag-upload [options] [<sensor>...]
     ruuvitag-upload -h | --help
     ruuvitag-upload --version
 
@@ -106,12 +434,110 @@ ARGUMENTS:
         readable alias to the address
         XX:XX:XX:XX:XX:XX=mysensor.
 
+        If sensors are given, they replace the sensors of
+        the configuration file. A sensor without an alias
+        still uses the alias and metadata defined for it in
+        the configuration file.
+
+    <entry>
+
+        The file name of a cache entry, as listed by cache
+        list. Paths are not accepted.
+
 OPTIONS:
 
     -u URL, --url=URL
 
         Where the measurements are uploaded to. If you don't
-        specify this, the measurements are written to stdout.
+        specify this or an MQTT broker, the measurements are
+        written to stdout.
+
+    --mqtt=URL
+
+        Publish the measurements to the MQTT broker at URL,
+        mqtt://HOST[:PORT] or mqtts://HOST[:PORT] for TLS,
+        instead of uploading them.
+
+    -f FORMAT, --format=FORMAT
+
+        The format of the measurements: json, influx or csv.
+        Cached measurements are uploaded in the same format.
+        By default json is used.
+
+    -o FILE, --output=FILE
+
+        Append the measurements to FILE instead of writing
+        them to stdout. Ignored if a URL or an MQTT broker is
+        given.
+
+    -t SECS, --timeout=SECS
+
+        Stop scanning after SECS seconds even if all sensors
+        have not been seen yet. By default scanning continues
+        until every sensor has been seen. In discovery mode
+        this is how long to listen for RuuviTags, 10 seconds
+        by default. Ignored in daemon mode.
+
+    -i SECS, --interval=SECS
+
+        Run in daemon mode: keep scanning and publish a set
+        of measurements every SECS seconds.
+
+    -m ADDR, --metrics=ADDR
+
+        Serve the latest measurements for Prometheus at
+        http://ADDR/metrics, e.g. 0.0.0.0:9521. Requires
+        daemon mode.
+
+    -d, --discover
+
+        Collect measurements from every RuuviTag, not just
+        from the given sensors. In discovery mode giving
+        sensors is optional.
+
+    -r FILE, --replay=FILE
+
+        Read advertisements from FILE instead of scanning
+        with Bluetooth. Use - to read from stdin. The
+        measurements are published as one set per timestamp,
+        or per interval if one is given.
+
+    -a ADAPTER, --adapter=ADAPTER
+
+        The Bluetooth adapter used for scanning, either by
+        name (e.g. hci1) or by address. By default the first
+        adapter of the system is used.
+
+    --no-reset
+
+        Don't reset the Bluetooth adapter before scanning.
+        By default the adapter is brought down and up again,
+        which disrupts other users of the adapter.
+
+    -c FILE, --config=FILE
+
+        Read the configuration from FILE. By default the
+        configuration is read from config.toml in the user
+        configuration directory (e.g.
+        ~/.config/ruuvitag-upload/config.toml), if it exists.
+
+    --no-cache
+
+        Don't cache measurements that could not be uploaded
+        or published.
+
+    --cache-dir=DIR
+
+        Cache measurements in DIR. Overrides the
+        RUUVITAG_UPLOAD_CACHE_DIR environment variable,
+        which in turn overrides the configuration file. By
+        default the user data directory is used (e.g.
+        ~/.local/share/ruuvitag-upload).
+
+    --older-than=SECS
+
+        Purge the cached sets that are older than SECS
+        seconds.
 
     -h, --help
 
@@ -120,12 +546,39 @@ OPTIONS:
     --version
 
         Show the version number.
+
+EXIT STATUS:
+
+    0   All sensors were seen.
+    1   An error occurred.
+    2   Scanning timed out before all sensors were seen.
 ";
 
 #[derive(Deserialize)]
 struct Args {
     arg_sensor: Vec<String>,
     flag_url: Option<String>,
+    flag_timeout: Option<u64>,
+    flag_replay: Option<String>,
+    flag_adapter: Option<String>,
+    flag_no_reset: bool,
+    flag_interval: Option<u64>,
+    flag_config: Option<String>,
+    flag_no_cache: bool,
+    flag_cache_dir: Option<String>,
+    flag_discover: bool,
+    cmd_scan: bool,
+    cmd_cache: bool,
+    cmd_list: bool,
+    cmd_show: bool,
+    cmd_flush: bool,
+    cmd_purge: bool,
+    arg_entry: Option<String>,
+    flag_older_than: Option<u64>,
+    flag_format: Option<Format>,
+    flag_output: Option<String>,
+    flag_metrics: Option<String>,
+    flag_mqtt: Option<String>,
 }
 
 fn parse_sensor(s: &str) -> (&str, &str) {
@@ -135,14 +588,66 @@ fn parse_sensor(s: &str) -> (&str, &str) {
     (address, alias)
 }
 
+/// Builds the set of sensors, keyed by address. Sensors given on the command line replace the
+/// ones in the configuration file, but take their alias and metadata from the configuration
+/// file unless an alias is given on the command line.
+fn build_sensors(args: &[String], configured: &[SensorConfig]) -> HashMap<String, Sensor> {
+    let from_config = |config: &SensorConfig| {
+        let address = config.address.to_uppercase();
+        let sensor = Sensor {
+            alias: config.alias.clone().unwrap_or_else(|| address.clone()),
+            metadata: config.metadata.clone(),
+        };
+        (address, sensor)
+    };
+
+    let configured: HashMap<String, Sensor> = configured.iter().map(from_config).collect();
+
+    if args.is_empty() {
+        return configured;
+    }
+
+    args.iter()
+        .map(|x| parse_sensor(x))
+        .map(|(address, alias)| {
+            let address = address.to_uppercase();
+            let metadata = configured
+                .get(&address)
+                .map(|sensor| sensor.metadata.clone())
+                .unwrap_or_default();
+            let alias = if alias.eq_ignore_ascii_case(&address) {
+                configured
+                    .get(&address)
+                    .map(|sensor| sensor.alias.clone())
+                    .unwrap_or_else(|| address.clone())
+            } else {
+                alias.to_string()
+            };
+            (address, Sensor { alias, metadata })
+        })
+        .collect()
+}
+
+/// Exit code used when some of the sensors were not seen before the scan timed out.
+const EXIT_MISSING_SENSORS: i32 = 2;
+
+/// How long to listen for advertisements in discovery mode if no timeout is given, seconds.
+const DEFAULT_DISCOVERY_TIMEOUT: u64 = 10;
+
+/// Interval the cache is thinned out to if none is configured, seconds.
+const DEFAULT_THIN_INTERVAL: u64 = 3600;
+
 fn main() {
-    if let Err(e) = run() {
-        eprintln!("error: {}", e);
-        process::exit(1);
+    match run() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
     }
 }
 
-fn run() -> Result<(), Error> {
+fn run() -> Result<i32, Error> {
     let version = format!(
         "{}.{}.{}",
         env!("CARGO_PKG_VERSION_MAJOR"),
@@ -154,47 +659,696 @@ fn run() -> Result<(), Error> {
         .and_then(|d| d.help(true).version(Some(version)).deserialize())
         .unwrap_or_else(|e| e.exit());
 
-    let sensors: HashMap<String, String> = args
-        .arg_sensor
-        .iter()
-        .map(|x| parse_sensor(x))
-        .map(|(address, alias)| (address.to_string(), alias.to_string()))
-        .collect();
+    let config = Config::load(args.flag_config.as_deref().map(Path::new))?;
+
+    let sensors = build_sensors(&args.arg_sensor, &config.sensors);
+
+    let discover = args.cmd_scan || args.flag_discover || config.discover.unwrap_or(false);
+
+    let flush_cache = args.cmd_cache && args.cmd_flush;
+    let cache_command = if !args.cmd_cache || flush_cache {
+        None
+    } else if args.cmd_list {
+        Some(CacheCommand::List)
+    } else if args.cmd_show {
+        Some(CacheCommand::Show(args.arg_entry.clone().unwrap()))
+    } else if args.cmd_purge {
+        match args.flag_older_than {
+            Some(age) => Some(CacheCommand::Purge(Duration::from_secs(age))),
+            None => {
+                return Err(failure::format_err!(
+                    "purging the cache requires --older-than"
+                ));
+            }
+        }
+    } else {
+        Some(CacheCommand::Export)
+    };
 
-    let measurements = collect_measurements(sensors)?;
+    if sensors.is_empty() && !discover && !args.cmd_cache {
+        return Err(failure::format_err!(
+            "no sensors given on the command line or in the configuration file"
+        ));
+    }
 
-    if let Some(url) = args.flag_url {
-        let result = upload_cached_measurements(&url);
+    let url = args.flag_url.or(config.url);
+    let timeout = match args.flag_timeout.or(config.timeout) {
+        Some(timeout) => Some(Duration::from_secs(timeout)),
+        // In discovery mode there is no way to tell when everything has been seen.
+        None if discover => Some(Duration::from_secs(DEFAULT_DISCOVERY_TIMEOUT)),
+        None => None,
+    };
+    let interval = args.flag_interval.or(config.interval);
+    if interval == Some(0) {
+        return Err(failure::format_err!(
+            "the interval must be greater than zero"
+        ));
+    }
+    let adapter = args.flag_adapter.or(config.adapter);
+    let reset = !args.flag_no_reset && config.reset.unwrap_or(true);
+    let cache = !args.flag_no_cache && config.cache.enabled.unwrap_or(true);
+    let cache_dir = resolve_cache_dir(
+        args.flag_cache_dir.map(PathBuf::from),
+        env::var_os(CACHE_DIR_VAR),
+        config.cache.dir,
+    )?;
+    let batch = match (config.cache.batch_size, config.cache.batch_bytes) {
+        (Some(0), _) | (_, Some(0)) => {
+            return Err(failure::format_err!(
+                "the batch size must be greater than zero"
+            ));
+        }
+        (None, None) => None,
+        (size, bytes) => Some(Batch {
+            size: size.unwrap_or(usize::MAX),
+            bytes: bytes.unwrap_or(u64::MAX),
+        }),
+    };
+    let cache_limits = CacheLimits {
+        entries: config.cache.max_entries,
+        bytes: config.cache.max_bytes,
+        age: config.cache.max_age.map(Duration::from_secs),
+        thin_interval: match config.cache.eviction.unwrap_or_default() {
+            Eviction::Oldest => None,
+            Eviction::Thin => Some(Duration::from_secs(
+                config.cache.thin_interval.unwrap_or(DEFAULT_THIN_INTERVAL),
+            )),
+        },
+    };
+    if cache_limits.entries == Some(0)
+        || cache_limits.bytes == Some(0)
+        || cache_limits.age == Some(Duration::from_secs(0))
+        || cache_limits.thin_interval == Some(Duration::from_secs(0))
+    {
+        return Err(failure::format_err!(
+            "the cache limits must be greater than zero"
+        ));
+    }
+    let format = args.flag_format.or(config.format).unwrap_or_default();
+    let output = args.flag_output.or(config.output).map(PathBuf::from);
 
-        // If uploading cached measurements failed, we try to cache the latest measurements.
-        if result.is_err() {
-            eprintln!("error: {}", result.unwrap_err());
-            cache_measurements(measurements)?;
-            return Ok(());
+    // These commands don't send anything, so they work without the upload or broker
+    // credentials.
+    if let Some(command) = cache_command {
+        return run_cache_command(command, &cache_dir, format, output.as_deref());
+    }
+
+    // Scanning only prints the tags, so it doesn't need the credentials either.
+    if args.cmd_scan {
+        let mut scanner = open_scanner(args.flag_replay.as_deref(), adapter.as_deref(), reset)?;
+        let measurements = collect_measurements(scanner.as_mut(), &sensors, timeout, true)?;
+        print!("{}", format_scan_table(&measurements));
+        return Ok(0);
+    }
+
+    let mqtt = match args.flag_mqtt.or(config.mqtt.url.clone()) {
+        Some(_) if url.is_some() => {
+            return Err(failure::format_err!(
+                "both a URL and an MQTT broker given, only one can be used"
+            ));
         }
+        Some(broker) => Some(MqttPublisher::new(&broker, &config.mqtt)?),
+        None => None,
+    };
 
-        let client = reqwest::Client::new();
+    let uploader = match url {
+        Some(url) => Some(Uploader::new(&url, format, &config.upload)?),
+        None => None,
+    };
+
+    let destination = Destination {
+        uploader,
+        mqtt,
+        output,
+        format,
+        cache,
+        cache_dir,
+        cache_limits,
+        batch,
+    };
+
+    // Only sending measurements can cache them, so only then is the directory needed.
+    if destination.cache && (destination.uploader.is_some() || destination.mqtt.is_some()) {
+        check_cache_dir(&destination.cache_dir)?;
+    }
 
-        let result = match client.post(&url).json(&measurements).send() {
-            Ok(response) => match response.error_for_status() {
-                Ok(response) => Ok(response),
-                Err(error) => Err(error),
-            },
-            Err(error) => Err(error),
+    if flush_cache {
+        return flush_cache_command(&destination);
+    }
+
+    let mut scanner = open_scanner(args.flag_replay.as_deref(), adapter.as_deref(), reset)?;
+
+    let metrics = args.flag_metrics.or(config.metrics);
+
+    if args.flag_replay.is_some() {
+        if metrics.is_some() {
+            return Err(failure::format_err!(
+                "the metrics endpoint is not available when replaying"
+            ));
+        }
+        return replay_measurements(scanner.as_mut(), &sensors, interval, discover, &destination);
+    }
+
+    if let Some(interval) = interval {
+        let metrics = match metrics {
+            Some(addr) => Some(MetricsServer::start(&addr)?),
+            None => None,
+        };
+        return run_daemon(
+            scanner.as_mut(),
+            &sensors,
+            Duration::from_secs(interval),
+            discover,
+            &destination,
+            metrics.as_ref(),
+        );
+    }
+
+    if metrics.is_some() {
+        return Err(failure::format_err!(
+            "the metrics endpoint is only available in daemon mode"
+        ));
+    }
+
+    let measurements = collect_measurements(scanner.as_mut(), &sensors, timeout, discover)?;
+
+    let code = report_missing_sensors(&sensors, &measurements);
+
+    publish_measurements(&destination, measurements)?;
+
+    Ok(code)
+}
+
+/// Opens the scanner replaying the given file, or stdin if it is `-`, or scanning with the given
+/// Bluetooth adapter if there is nothing to replay.
+fn open_scanner(
+    replay: Option<&str>,
+    adapter: Option<&str>,
+    reset: bool,
+) -> Result<Box<dyn Scanner>, Error> {
+    Ok(match replay {
+        Some("-") => Box::new(ReplayScanner::new(BufReader::new(io::stdin()))),
+        Some(path) => Box::new(ReplayScanner::new(BufReader::new(fs::File::open(path)?))),
+        None => Box::new(BluezScanner::new(adapter, reset)?),
+    })
+}
+
+/// Where and how the measurements are published.
+struct Destination {
+    /// Uploads the measurements to the URL, if one is given.
+    uploader: Option<Uploader>,
+    /// The broker the measurements are published to instead of uploading them.
+    mqtt: Option<MqttPublisher>,
+    /// The file the measurements are appended to if there is no URL. If neither is given, the
+    /// measurements are written to stdout.
+    output: Option<PathBuf>,
+    format: Format,
+    /// Whether measurements that could not be uploaded or published are cached.
+    cache: bool,
+    /// Where the measurements are cached.
+    cache_dir: PathBuf,
+    cache_limits: CacheLimits,
+    /// Limits of sending cached measurements in batches. If not given, each cached set is sent
+    /// on its own.
+    batch: Option<Batch>,
+}
+
+impl Destination {
+    /// Returns where the measurements are sent, if anywhere.
+    fn sink(&self) -> Option<&dyn Sink> {
+        match (&self.uploader, &self.mqtt) {
+            (Some(uploader), _) => Some(uploader),
+            (None, Some(mqtt)) => Some(mqtt),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Limits of a batch of cached sets of measurements sent at once.
+struct Batch {
+    /// Maximum number of sets.
+    size: usize,
+    /// Maximum total size of the cache files, bytes. A file that is larger than this is sent in
+    /// a batch of its own.
+    bytes: u64,
+}
+
+/// Limits on the size of the cache, applied whenever measurements are cached.
+struct CacheLimits {
+    /// Maximum number of cached sets.
+    entries: Option<usize>,
+    /// Maximum total size of the cache files, bytes.
+    bytes: Option<u64>,
+    /// Cached sets older than this are discarded.
+    age: Option<Duration>,
+    /// If given, a cache that is over the limits is first thinned out to one set per interval
+    /// before discarding the oldest sets.
+    thin_interval: Option<Duration>,
+}
+
+/// Somewhere the measurements are sent to over the network.
+trait Sink {
+    fn send(&self, measurements: &HashMap<String, Measurement>) -> Result<(), Error>;
+
+    /// Sends several sets of measurements at once.
+    fn send_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error>;
+}
+
+impl Sink for Uploader {
+    fn send(&self, measurements: &HashMap<String, Measurement>) -> Result<(), Error> {
+        self.upload(measurements)
+    }
+
+    fn send_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error> {
+        self.upload_batch(batch)
+    }
+}
+
+impl Sink for MqttPublisher {
+    fn send(&self, measurements: &HashMap<String, Measurement>) -> Result<(), Error> {
+        self.publish_measurements(measurements)
+    }
+
+    fn send_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error> {
+        self.publish_batch(batch)
+    }
+}
+
+/// A command working on the cached measurements instead of scanning. Flushing the cache is
+/// handled by `flush_cache_command` since it needs somewhere to send the measurements.
+enum CacheCommand {
+    /// Lists the cache entries.
+    List,
+    /// Prints the cache entry with the given name.
+    Show(String),
+    /// Removes the cache entries older than the given age.
+    Purge(Duration),
+    /// Prints all cached measurements as one document.
+    Export,
+}
+
+/// Runs a cache command.
+fn run_cache_command(
+    command: CacheCommand,
+    cache_dir: &Path,
+    format: Format,
+    output: Option<&Path>,
+) -> Result<i32, Error> {
+    match command {
+        CacheCommand::List => {
+            let mut rows = Vec::new();
+            for entry in list_cache_entries(cache_dir)? {
+                let sensors = read_cached_measurements(&entry.path)
+                    .map(|measurements| measurements.len())
+                    .ok();
+                rows.push((entry, sensors));
+            }
+            print!("{}", format_cache_table(&rows));
+        }
+        CacheCommand::Show(entry) => {
+            let measurements = read_cached_measurements(&cache_entry_path(cache_dir, &entry)?)?;
+            println!("{}", format.render(&measurements).trim_end());
+        }
+        CacheCommand::Purge(age) => {
+            let purged = purge_cache(cache_dir, age, SystemTime::now())?;
+            eprintln!("purged {} cached sets of measurements", purged);
+        }
+        CacheCommand::Export => {
+            export_cache(cache_dir, format, output)?;
+        }
+    }
+
+    Ok(0)
+}
+
+/// Sends the cached measurements like publishing would.
+fn flush_cache_command(destination: &Destination) -> Result<i32, Error> {
+    let sink = destination
+        .sink()
+        .ok_or_else(|| failure::format_err!("no URL or MQTT broker to flush the cache to"))?;
+    send_cached_measurements(sink, &destination.cache_dir, destination.batch.as_ref())?;
+
+    Ok(0)
+}
+
+/// Returns the path of the cache entry with the given name. Anything but a plain file name is
+/// rejected, so that only files in the cache can be read.
+fn cache_entry_path(cache_dir: &Path, entry: &str) -> Result<PathBuf, Error> {
+    let mut components = Path::new(entry).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(cache_dir.join(entry)),
+        _ => Err(failure::format_err!("invalid cache entry: {}", entry)),
+    }
+}
+
+/// Removes the cache entries older than the given age. Returns how many were removed.
+fn purge_cache(cache_dir: &Path, age: Duration, now: SystemTime) -> Result<usize, Error> {
+    let limits = CacheLimits {
+        entries: None,
+        bytes: None,
+        age: Some(age),
+        thin_interval: None,
+    };
+
+    let (expired, _) = evict_cache_entries(list_cache_entries(cache_dir)?, &limits, now);
+
+    for entry in &expired {
+        fs::remove_file(&entry.path)?;
+    }
+
+    Ok(expired.len())
+}
+
+/// Writes all cached measurements to stdout as one document, or appends them to the output
+/// file like regular runs would. Entries that can't be read are skipped.
+fn export_cache(cache_dir: &Path, format: Format, output: Option<&Path>) -> Result<(), Error> {
+    let mut batch = Vec::new();
+
+    for path in find_cached_measurements(cache_dir)? {
+        match read_cached_measurements(&path) {
+            Ok(measurements) => batch.push(measurements),
+            Err(error) => eprintln!("warning: skipping cache entry: {}", error),
+        }
+    }
+
+    match output {
+        Some(path) => {
+            for measurements in &batch {
+                append_measurements(path, format, measurements)?;
+            }
+        }
+        None => println!("{}", format.render_batch(&batch).trim_end()),
+    }
+
+    Ok(())
+}
+
+/// Publishes the replayed measurements as one set per timestamp, or per interval if one is
+/// given, from oldest to newest. After sending a set fails, the rest are cached without trying
+/// to send them. Sensors that don't appear in any set are reported as missing.
+fn replay_measurements(
+    scanner: &mut dyn Scanner,
+    sensors: &HashMap<String, Sensor>,
+    interval: Option<u64>,
+    discover: bool,
+    destination: &Destination,
+) -> Result<i32, Error> {
+    let adv_rx = scanner.start()?;
+
+    let sets = group_replayed_measurements(&adv_rx, sensors, interval, discover);
+
+    scanner.stop()?;
+
+    let mut seen = HashMap::new();
+    let mut reachable = true;
+
+    for measurements in sets.into_values() {
+        seen.extend(measurements.clone());
+
+        let result = match destination.sink() {
+            // Once sending has failed, retrying each remaining set would mostly wait for the
+            // retry delays, so the rest are cached right away.
+            Some(_) if !reachable => {
+                if destination.cache && !measurements.is_empty() {
+                    cache_measurements(destination, measurements)
+                } else {
+                    Ok(())
+                }
+            }
+            Some(sink) => send_measurements(destination, sink, measurements).map(|sent| {
+                if !sent {
+                    eprintln!("warning: not sending the remaining replayed measurements");
+                }
+                reachable = sent;
+            }),
+            None => publish_measurements(destination, measurements),
         };
 
-        // If uploading the latest measurements failed, we try to cache them for later uploading.
-        if result.is_err() {
-            eprintln!("error: {}", result.unwrap_err());
-            cache_measurements(measurements)?;
+        // Like in daemon mode, failing to publish one set should not drop the rest.
+        if let Err(error) = result {
+            eprintln!("error: {}", error);
+        }
+    }
+
+    Ok(report_missing_sensors(sensors, &seen))
+}
+
+/// Keeps scanning and publishes the latest measurement of each sensor once every interval. The
+/// metrics server, if any, is updated with the same measurements. Returns only if the scanner
+/// runs out of advertisements.
+fn run_daemon(
+    scanner: &mut dyn Scanner,
+    sensors: &HashMap<String, Sensor>,
+    interval: Duration,
+    discover: bool,
+    destination: &Destination,
+    metrics: Option<&MetricsServer>,
+) -> Result<i32, Error> {
+    let adv_rx = scanner.start()?;
+
+    let mut deadline = Instant::now() + interval;
+
+    loop {
+        let mut measurements = HashMap::new();
+
+        let scanning = receive_measurements(
+            &adv_rx,
+            sensors,
+            Some(deadline),
+            false,
+            discover,
+            &mut measurements,
+        );
+
+        let code = report_missing_sensors(sensors, &measurements);
+
+        if let Some(metrics) = metrics {
+            metrics.update(&measurements);
         }
+
+        // Failing to publish one set of measurements should not stop the daemon.
+        if let Err(error) = publish_measurements(destination, measurements) {
+            eprintln!("error: {}", error);
+        }
+
+        if !scanning {
+            scanner.stop()?;
+            return Ok(code);
+        }
+
+        deadline = next_deadline(deadline, interval, Instant::now());
+    }
+}
+
+/// Returns the deadline of the next interval. If publishing took so long that it would already
+/// have passed, the next interval starts now instead, so that the missed intervals are skipped
+/// rather than run back to back without scanning.
+fn next_deadline(deadline: Instant, interval: Duration, now: Instant) -> Instant {
+    let next = deadline + interval;
+    if next > now {
+        next
     } else {
-        println!("{}", serde_json::to_string(&measurements).unwrap());
+        now + interval
+    }
+}
+
+/// Uploads the measurements to the destination URL or publishes them to the MQTT broker, or
+/// writes them to the output file or stdout if neither is given.
+fn publish_measurements(
+    destination: &Destination,
+    measurements: HashMap<String, Measurement>,
+) -> Result<(), Error> {
+    if let Some(sink) = destination.sink() {
+        send_measurements(destination, sink, measurements)?;
+    } else if let Some(ref path) = destination.output {
+        append_measurements(path, destination.format, &measurements)?;
+    } else {
+        let output = destination.format.render(&measurements);
+        println!("{}", output.trim_end());
+    }
+
+    Ok(())
+}
+
+/// Sends the cached measurements and then the given ones. If sending fails and caching is
+/// enabled, the given measurements are cached. Returns whether sending succeeded.
+fn send_measurements(
+    destination: &Destination,
+    sink: &dyn Sink,
+    measurements: HashMap<String, Measurement>,
+) -> Result<bool, Error> {
+    // If sending cached measurements failed, we try to cache the latest measurements.
+    if let Err(error) =
+        send_cached_measurements(sink, &destination.cache_dir, destination.batch.as_ref())
+    {
+        eprintln!("error: {}", error);
+        if destination.cache && !measurements.is_empty() {
+            cache_measurements(destination, measurements)?;
+        }
+        return Ok(false);
+    }
+
+    // Nothing was seen, so there is nothing to send or cache.
+    if measurements.is_empty() {
+        return Ok(true);
+    }
+
+    // If sending the latest measurements failed, we try to cache them for later sending.
+    if let Err(error) = sink.send(&measurements) {
+        eprintln!("error: {}", error);
+        if destination.cache {
+            cache_measurements(destination, measurements)?;
+        }
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Appends the measurements to a file. The header of the format is only written if the file is
+/// empty. An empty set of measurements adds nothing but the header.
+fn append_measurements(
+    path: &Path,
+    format: Format,
+    measurements: &HashMap<String, Measurement>,
+) -> Result<(), Error> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|error| failure::format_err!("failed to open {}: {}", path.display(), error))?;
+
+    let mut output = String::new();
+
+    if file.metadata()?.len() == 0 {
+        if let Some(header) = format.header() {
+            output.push_str(&header);
+        }
     }
 
+    if !measurements.is_empty() {
+        output.push_str(&format.render_records(measurements));
+
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+
+    file.write_all(output.as_bytes())?;
+
     Ok(())
 }
 
+/// Formats the measurements found by the scan command as a table, followed by a line of sensor
+/// arguments that can be pasted to the command line.
+fn format_scan_table(measurements: &HashMap<String, Measurement>) -> String {
+    fn value<T: std::fmt::Display>(value: Option<T>) -> String {
+        value.map_or_else(|| "-".to_string(), |value| value.to_string())
+    }
+
+    let mut rows: Vec<(&String, &Measurement)> = measurements.iter().collect();
+    rows.sort_by(|a, b| a.1.address.cmp(&b.1.address));
+
+    let mut table = format!(
+        "{:<17}  {:>4}  {:>6}  {:>11}  {:>8}  {:>8}  {:>7}  {}\n",
+        "ADDRESS", "RSSI", "FORMAT", "TEMPERATURE", "HUMIDITY", "PRESSURE", "BATTERY", "ALIAS"
+    );
+
+    let mut sensors = Vec::new();
+
+    for (alias, measurement) in rows {
+        // Suggest an alias similar to the names the Ruuvi apps use for unknown tags.
+        let alias = if *alias == measurement.address {
+            let digits: Vec<char> = measurement.address.chars().filter(|c| *c != ':').collect();
+            let suffix: String = digits[digits.len().saturating_sub(4)..].iter().collect();
+            format!("ruuvi-{}", suffix.to_lowercase())
+        } else {
+            alias.clone()
+        };
+
+        table.push_str(&format!(
+            "{:<17}  {:>4}  {:>6}  {:>11}  {:>8}  {:>8}  {:>7}  {}\n",
+            measurement.address,
+            value(measurement.rssi),
+            value(measurement.data_format),
+            value(measurement.temperature.map(|x| format!("{:.2}", x))),
+            value(measurement.humidity.map(|x| format!("{:.2}", x))),
+            value(measurement.pressure.map(|x| format!("{:.2}", x))),
+            value(measurement.battery_potential.map(|x| format!("{:.3}", x))),
+            alias
+        ));
+
+        sensors.push(format!("{}={}", measurement.address, alias));
+    }
+
+    table.push_str(&format!("\n{}\n", sensors.join(" ")));
+
+    table
+}
+
+/// Formats the cache entries as a table: when each was cached, how many sensors it has
+/// measurements from, the size of its file and its name. The number of sensors is missing from
+/// entries that can't be read.
+fn format_cache_table(rows: &[(CacheEntry, Option<usize>)]) -> String {
+    let mut table = format!(
+        "{:>10}  {:>7}  {:>7}  {}\n",
+        "CACHED", "SENSORS", "BYTES", "ENTRY"
+    );
+
+    for (entry, sensors) in rows {
+        table.push_str(&format!(
+            "{:>10}  {:>7}  {:>7}  {}\n",
+            entry
+                .created
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs()),
+            sensors.map_or_else(|| "-".to_string(), |sensors| sensors.to_string()),
+            entry.bytes,
+            entry.path.file_name().unwrap().to_string_lossy()
+        ));
+    }
+
+    table
+}
+
+/// Prints a warning for each sensor that has no measurement. Returns the exit code to use.
+fn report_missing_sensors(
+    sensors: &HashMap<String, Sensor>,
+    measurements: &HashMap<String, Measurement>,
+) -> i32 {
+    let missing = find_missing_sensors(sensors, measurements);
+
+    for (address, alias) in &missing {
+        if address == alias {
+            eprintln!("warning: no measurements from {}", address);
+        } else {
+            eprintln!("warning: no measurements from {} ({})", alias, address);
+        }
+    }
+
+    if missing.is_empty() {
+        0
+    } else {
+        EXIT_MISSING_SENSORS
+    }
+}
+
+/// Returns the `(address, alias)` pairs of the sensors that have no measurement, sorted by alias.
+fn find_missing_sensors<'a>(
+    sensors: &'a HashMap<String, Sensor>,
+    measurements: &HashMap<String, Measurement>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut missing: Vec<(&str, &str)> = sensors
+        .iter()
+        .filter(|(_, sensor)| !measurements.contains_key(&sensor.alias))
+        .map(|(address, sensor)| (address.as_str(), sensor.alias.as_str()))
+        .collect();
+
+    missing.sort_by_key(|(_, alias)| *alias);
+
+    missing
+}
+
 fn find_cached_measurements(cache_dir: &Path) -> Result<Vec<std::path::PathBuf>, Error> {
     let mut result = Vec::new();
 
@@ -224,129 +1378,532 @@ fn find_cached_measurements(cache_dir: &Path) -> Result<Vec<std::path::PathBuf>,
     Ok(result)
 }
 
-fn upload_cached_measurements(url: &str) -> Result<(), Error> {
-    let paths = find_cached_measurements(&get_cache_dir()?)?;
+/// Sends the cached measurements from oldest to newest, one set at a time or in batches. The
+/// cache files are removed once they have been sent. Cache files that can't be parsed are moved
+/// out of the way, and how many there were is reported at the end.
+fn send_cached_measurements(
+    sink: &dyn Sink,
+    cache_dir: &Path,
+    batch: Option<&Batch>,
+) -> Result<(), Error> {
+    let mut corrupt = 0;
+
+    let result = send_cache_entries(sink, cache_dir, batch, &mut corrupt);
+
+    if corrupt > 0 {
+        eprintln!(
+            "warning: moved {} corrupt cache entries to {}",
+            corrupt,
+            cache_dir.join(CORRUPT_DIR).display()
+        );
+    }
 
-    let client = reqwest::Client::new();
+    result
+}
 
-    for path in paths {
-        let file = fs::File::open(&path)?;
-        let reader = BufReader::new(file);
-        let measurements: HashMap<String, Measurement> = serde_json::from_reader(reader)?;
-        client
-            .post(url)
-            .json(&measurements)
-            .send()?
-            .error_for_status()?;
-        fs::remove_file(&path)?;
+fn send_cache_entries(
+    sink: &dyn Sink,
+    cache_dir: &Path,
+    batch: Option<&Batch>,
+    corrupt: &mut usize,
+) -> Result<(), Error> {
+    let paths = find_cached_measurements(cache_dir)?;
+
+    let batch = match batch {
+        Some(batch) => batch,
+        None => {
+            for path in paths {
+                match read_cache_entry(cache_dir, &path)? {
+                    Some(measurements) => sink.send(&measurements)?,
+                    None => {
+                        *corrupt += 1;
+                        continue;
+                    }
+                }
+                fs::remove_file(&path)?;
+            }
+            return Ok(());
+        }
+    };
+
+    for paths in batch_cached_measurements(paths, batch)? {
+        let mut sent = Vec::new();
+        let mut measurements = Vec::new();
+        for path in paths {
+            match read_cache_entry(cache_dir, &path)? {
+                Some(entry) => {
+                    sent.push(path);
+                    measurements.push(entry);
+                }
+                None => *corrupt += 1,
+            }
+        }
+        if measurements.is_empty() {
+            continue;
+        }
+        sink.send_batch(&measurements)?;
+        for path in &sent {
+            fs::remove_file(path)?;
+        }
     }
 
     Ok(())
 }
 
-fn get_cache_dir() -> Result<std::path::PathBuf, Error> {
+/// Splits the cache files to batches within the limits, keeping them in order.
+fn batch_cached_measurements(
+    paths: Vec<PathBuf>,
+    batch: &Batch,
+) -> Result<Vec<Vec<PathBuf>>, Error> {
+    let mut batches = Vec::new();
+    let mut current: Vec<PathBuf> = Vec::new();
+    let mut bytes = 0;
+
+    for path in paths {
+        let len = fs::metadata(&path)?.len();
+        if !current.is_empty() && (current.len() >= batch.size || bytes + len > batch.bytes) {
+            batches.push(current);
+            current = Vec::new();
+            bytes = 0;
+        }
+        current.push(path);
+        bytes += len;
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+
+    Ok(batches)
+}
+
+fn read_cached_measurements(path: &Path) -> Result<HashMap<String, Measurement>, Error> {
+    let read = || -> Result<_, Error> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    };
+    read().map_err(|error| failure::format_err!("failed to read {}: {}", path.display(), error))
+}
+
+/// Reads a cache entry for sending. An entry that can't be parsed would block every entry after
+/// it, so it is moved to the corrupt subdirectory of the cache and None is returned instead.
+fn read_cache_entry(
+    cache_dir: &Path,
+    path: &Path,
+) -> Result<Option<HashMap<String, Measurement>>, Error> {
+    let contents = fs::read(path)?;
+
+    let error = match serde_json::from_slice(&contents) {
+        Ok(measurements) => return Ok(Some(measurements)),
+        Err(error) => error,
+    };
+
+    let corrupt_dir = cache_dir.join(CORRUPT_DIR);
+    let corrupt_path = corrupt_dir.join(path.file_name().unwrap());
+
+    eprintln!(
+        "warning: moving corrupt cache entry {} to {}: {}",
+        path.display(),
+        corrupt_dir.display(),
+        error
+    );
+
+    fs::create_dir_all(&corrupt_dir)?;
+    fs::rename(path, corrupt_path)?;
+
+    Ok(None)
+}
+
+/// Subdirectory of the cache that cache entries which can't be parsed are moved to.
+const CORRUPT_DIR: &str = "corrupt";
+
+/// Environment variable that overrides the cache directory of the configuration file.
+const CACHE_DIR_VAR: &str = "RUUVITAG_UPLOAD_CACHE_DIR";
+
+/// Returns the cache directory used when none is given explicitly.
+fn default_cache_dir() -> Result<PathBuf, Error> {
     match ProjectDirs::from("dev", "otimperi", "ruuvitag-upload") {
         None => Err(failure::format_err!("failed to get cache dir location")),
         Some(dir) => Ok(dir.data_dir().to_path_buf()),
     }
 }
 
-fn cache_measurements(measurements: HashMap<String, Measurement>) -> Result<(), Error> {
-    let mut path = get_cache_dir()?;
+/// Returns the cache directory given on the command line, in the environment variable or in
+/// the configuration file, in that order of precedence, or the default one.
+fn resolve_cache_dir(
+    arg: Option<PathBuf>,
+    var: Option<OsString>,
+    config: Option<PathBuf>,
+) -> Result<PathBuf, Error> {
+    let var = var.filter(|dir| !dir.is_empty()).map(PathBuf::from);
+    match arg.or(var).or(config) {
+        Some(dir) => Ok(dir),
+        None => default_cache_dir(),
+    }
+}
 
-    path.push(format!(
-        "{}.json",
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    ));
+/// Makes sure that the cache directory exists and is writable, so that a misconfigured
+/// directory is reported right away instead of when measurements would have to be cached.
+fn check_cache_dir(cache_dir: &Path) -> Result<(), Error> {
+    let check = || -> io::Result<()> {
+        fs::create_dir_all(cache_dir)?;
+        let path = cache_dir.join(format!(".write-test-{}.tmp", process::id()));
+        fs::File::create(&path)?;
+        fs::remove_file(&path)
+    };
+
+    check().map_err(|error| {
+        failure::format_err!(
+            "the cache directory {} is not writable: {}",
+            cache_dir.display(),
+            error
+        )
+    })
+}
 
-    eprintln!("caching measurements to {}", path.display());
+fn cache_measurements(
+    destination: &Destination,
+    measurements: HashMap<String, Measurement>,
+) -> Result<(), Error> {
+    let path = write_cache_entry(&destination.cache_dir, &measurements)?;
 
-    std::fs::create_dir_all(path.parent().unwrap())?;
+    eprintln!("cached measurements to {}", path.display());
 
-    let mut file = std::fs::File::create(path)?;
+    limit_cache(
+        &destination.cache_dir,
+        &destination.cache_limits,
+        SystemTime::now(),
+    )
+}
 
-    let json = serde_json::to_string(&measurements)?;
+/// A cached set of measurements.
+struct CacheEntry {
+    path: PathBuf,
+    /// When the set was cached.
+    created: SystemTime,
+    /// Size of the cache file.
+    bytes: u64,
+}
 
-    file.write_all(&json.into_bytes())?;
+/// Returns the entries of the cache from oldest to newest. Stale temporary files are removed
+/// on the way.
+fn list_cache_entries(cache_dir: &Path) -> Result<Vec<CacheEntry>, Error> {
+    let mut entries = Vec::new();
 
-    Ok(())
+    if cache_dir.is_dir() {
+        remove_stale_temp_files(cache_dir, SystemTime::now())?;
+    }
+
+    for path in find_cached_measurements(cache_dir)? {
+        let metadata = fs::metadata(&path)?;
+        entries.push(CacheEntry {
+            path,
+            created: metadata.modified()?,
+            bytes: metadata.len(),
+        });
+    }
+
+    Ok(entries)
 }
 
-fn collect_measurements(
-    sensors: HashMap<String, String>,
-) -> Result<HashMap<String, Measurement>, Error> {
-    let manager = rumble::bluez::manager::Manager::new()?;
+/// Discards cache entries to keep the cache within the limits, warning about the data lost.
+/// The corrupt entries count towards the maximum size and age too, but they only get the space
+/// that the cached measurements leave over.
+fn limit_cache(cache_dir: &Path, limits: &CacheLimits, now: SystemTime) -> Result<(), Error> {
+    let entries = list_cache_entries(cache_dir)?;
+    let total_bytes: u64 = entries.iter().map(|entry| entry.bytes).sum();
+
+    let (expired, evicted) = evict_cache_entries(entries, limits, now);
+
+    if let (false, Some(age)) = (expired.is_empty(), limits.age) {
+        eprintln!(
+            "warning: discarding {} cached sets of measurements older than {} seconds",
+            expired.len(),
+            age.as_secs()
+        );
+    }
+    if !evicted.is_empty() {
+        eprintln!(
+            "warning: the cache is over its limits, discarding {} cached sets of measurements",
+            evicted.len()
+        );
+    }
 
-    let mut adapter = manager.adapters()?.into_iter().nth(0).unwrap();
+    for entry in expired.iter().chain(&evicted) {
+        fs::remove_file(&entry.path)?;
+    }
 
-    adapter = manager.down(&adapter)?;
-    adapter = manager.up(&adapter)?;
+    let kept_bytes = total_bytes
+        - expired
+            .iter()
+            .chain(&evicted)
+            .map(|entry| entry.bytes)
+            .sum::<u64>();
+
+    let corrupt_limits = CacheLimits {
+        entries: None,
+        bytes: limits.bytes.map(|bytes| bytes.saturating_sub(kept_bytes)),
+        age: limits.age,
+        thin_interval: None,
+    };
+    let (expired, evicted) = evict_cache_entries(
+        list_cache_entries(&cache_dir.join(CORRUPT_DIR))?,
+        &corrupt_limits,
+        now,
+    );
 
-    let central = Arc::new(adapter.connect()?);
+    if !expired.is_empty() || !evicted.is_empty() {
+        eprintln!(
+            "warning: discarding {} corrupt cache entries",
+            expired.len() + evicted.len()
+        );
+    }
 
-    let central_clone = central.clone();
+    for entry in expired.iter().chain(&evicted) {
+        fs::remove_file(&entry.path)?;
+    }
 
-    let (meas_tx, meas_rx) = channel();
+    Ok(())
+}
 
-    central.on_event(Box::new(move |event| {
-        if let Some(result) = on_event(&central_clone, event) {
-            if let Ok(measurement) = result {
-                let _ = meas_tx.send(measurement);
-            }
+/// Chooses the cache entries, given from oldest to newest, that are discarded. Returns the
+/// entries older than the maximum age, and the entries evicted to get the rest within the
+/// limits: first by thinning them out, if configured, and then by discarding the oldest.
+fn evict_cache_entries(
+    entries: Vec<CacheEntry>,
+    limits: &CacheLimits,
+    now: SystemTime,
+) -> (Vec<CacheEntry>, Vec<CacheEntry>) {
+    let (expired, mut kept): (Vec<_>, Vec<_>) = entries.into_iter().partition(|entry| {
+        match (limits.age, now.duration_since(entry.created)) {
+            (Some(max_age), Ok(age)) => age > max_age,
+            _ => false,
         }
-    }));
+    });
 
-    central.start_scan()?;
+    let max_entries = limits.entries.unwrap_or(usize::MAX);
+    let max_bytes = limits.bytes.unwrap_or(u64::MAX);
+    let total_bytes = |entries: &[CacheEntry]| entries.iter().map(|e| e.bytes).sum::<u64>();
 
-    let mut measurements = HashMap::new();
+    let mut evicted = Vec::new();
 
-    loop {
-        let measurement = meas_rx.recv()?;
-        if let Some(alias) = sensors.get(&measurement.address) {
-            measurements.insert(alias.clone(), measurement);
-            if measurements.len() == sensors.len() {
-                break;
+    if let Some(interval) = limits.thin_interval {
+        if kept.len() > max_entries || total_bytes(&kept) > max_bytes {
+            let mut thinned: Vec<CacheEntry> = Vec::new();
+            for entry in kept {
+                match thinned.last() {
+                    Some(last) if entry.created < last.created + interval => evicted.push(entry),
+                    _ => thinned.push(entry),
+                }
             }
+            kept = thinned;
         }
     }
 
-    central.stop_scan()?;
+    let mut bytes = total_bytes(&kept);
+    let mut count = 0;
+    while kept.len() - count > max_entries || bytes > max_bytes {
+        bytes -= kept[count].bytes;
+        count += 1;
+    }
+    evicted.extend(kept.drain(..count));
 
-    Ok(measurements)
+    (expired, evicted)
 }
 
-fn on_event(
-    central: &ConnectedAdapter,
-    event: CentralEvent,
-) -> Option<Result<Measurement, ParseError>> {
-    match event {
-        CentralEvent::DeviceDiscovered(addr) => on_event_with_address(central, addr),
-        CentralEvent::DeviceUpdated(addr) => on_event_with_address(central, addr),
-        _ => None,
+/// Writes the measurements to a new entry in the cache directory. The entry is written to a
+/// temporary file first and renamed once it is on disk, so that a crash can't leave a partial
+/// entry behind.
+fn write_cache_entry(
+    cache_dir: &Path,
+    measurements: &HashMap<String, Measurement>,
+) -> Result<PathBuf, Error> {
+    fs::create_dir_all(cache_dir)?;
+
+    let name = cache_entry_name();
+    let path = cache_dir.join(&name);
+    let temp_path = cache_dir.join(format!(".{}.tmp", name));
+
+    let write = || -> Result<(), Error> {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(serde_json::to_string(measurements)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    };
+
+    if let Err(error) = write() {
+        let _ = fs::remove_file(&temp_path);
+        return Err(error);
     }
+
+    // The rename is only durable once the directory is synced too.
+    fs::File::open(cache_dir)?.sync_all()?;
+
+    Ok(path)
 }
 
-fn on_event_with_address(
-    central: &ConnectedAdapter,
-    address: BDAddr,
-) -> Option<Result<Measurement, ParseError>> {
-    match central.peripheral(address) {
-        Some(peripheral) => match to_sensor_value(peripheral) {
-            Ok(values) => Some(Ok(Measurement::new(address, values))),
-            Err(e) => Some(Err(e)),
-        },
-        None => None,
+/// How old a temporary cache file has to be to count as left behind by an interrupted write.
+/// Younger ones may still be written by another process.
+const STALE_TEMP_FILE_AGE: Duration = Duration::from_secs(300);
+
+/// Removes the temporary files of cache entries whose writing was interrupted, e.g. by a crash.
+fn remove_stale_temp_files(cache_dir: &Path, now: SystemTime) -> Result<(), Error> {
+    for entry in fs::read_dir(cache_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !entry.file_type()?.is_file() || !name.starts_with('.') || !name.ends_with(".tmp") {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if now
+            .duration_since(modified)
+            .is_ok_and(|age| age > STALE_TEMP_FILE_AGE)
+        {
+            eprintln!(
+                "warning: removing {} left behind by an interrupted write",
+                entry.path().display()
+            );
+            fs::remove_file(entry.path())?;
+        }
     }
+
+    Ok(())
 }
 
-fn to_sensor_value<T: Peripheral>(peripheral: T) -> Result<SensorValues, ParseError> {
-    match peripheral.properties().manufacturer_data {
-        Some(data) => from_manufacturer_data(&data),
-        None => Err(ParseError::EmptyValue),
+/// Returns the file name of a new cache entry. The names start with the time the entry was
+/// created, kept strictly increasing within the process, so that they sort from oldest to
+/// newest. The random suffix keeps entries created by different processes apart.
+fn cache_entry_name() -> String {
+    static LATEST: Mutex<Duration> = Mutex::new(Duration::from_secs(0));
+
+    let mut latest = LATEST.lock().unwrap();
+
+    let mut now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    if now <= *latest {
+        now = *latest + Duration::from_nanos(1);
     }
+    *latest = now;
+
+    format!(
+        "{}-{:09}-{:08x}.json",
+        now.as_secs(),
+        now.subsec_nanos(),
+        rand::random::<u32>()
+    )
+}
+
+/// Scans for measurements from the given sensors. Scanning stops when every sensor has been
+/// seen, when the scanner runs out of advertisements or, if a timeout is given, when the timeout
+/// expires. In the latter cases the returned map contains only the sensors that were seen. In
+/// discovery mode measurements are collected from every RuuviTag until the timeout expires.
+fn collect_measurements(
+    scanner: &mut dyn Scanner,
+    sensors: &HashMap<String, Sensor>,
+    timeout: Option<Duration>,
+    discover: bool,
+) -> Result<HashMap<String, Measurement>, Error> {
+    let adv_rx = scanner.start()?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let mut measurements = HashMap::new();
+
+    receive_measurements(
+        &adv_rx,
+        sensors,
+        deadline,
+        !discover,
+        discover,
+        &mut measurements,
+    );
+
+    scanner.stop()?;
+
+    Ok(measurements)
+}
+
+/// Receives advertisements and stores the measurements of the given sensors, replacing older
+/// measurements of the same sensor. If `discover` is true, measurements of unknown RuuviTags are
+/// stored too, using their address as the alias. Receiving stops when the deadline passes, when
+/// the channel is closed or, if `until_all_seen` is true, when every sensor has been seen.
+/// Returns false if the channel was closed.
+fn receive_measurements(
+    adv_rx: &Receiver<Advertisement>,
+    sensors: &HashMap<String, Sensor>,
+    deadline: Option<Instant>,
+    until_all_seen: bool,
+    discover: bool,
+    measurements: &mut HashMap<String, Measurement>,
+) -> bool {
+    while !until_all_seen || measurements.len() < sensors.len() {
+        let advertisement = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match adv_rx.recv_timeout(remaining) {
+                    Ok(advertisement) => advertisement,
+                    Err(RecvTimeoutError::Timeout) => break,
+                    Err(RecvTimeoutError::Disconnected) => return false,
+                }
+            }
+            None => match adv_rx.recv() {
+                Ok(advertisement) => advertisement,
+                Err(RecvError) => return false,
+            },
+        };
+        if let Some((alias, measurement)) = to_measurement(&advertisement, sensors, discover) {
+            measurements.insert(alias, measurement);
+        }
+    }
+
+    true
+}
+
+/// Receives all replayed advertisements and groups their measurements to sets by timestamp, or
+/// by interval of the timestamps if one is given. Within a set, the latest measurement of each
+/// sensor is kept.
+fn group_replayed_measurements(
+    adv_rx: &Receiver<Advertisement>,
+    sensors: &HashMap<String, Sensor>,
+    interval: Option<u64>,
+    discover: bool,
+) -> BTreeMap<u64, HashMap<String, Measurement>> {
+    let mut sets: BTreeMap<u64, HashMap<String, Measurement>> = BTreeMap::new();
+
+    for advertisement in adv_rx.iter() {
+        let key = match interval {
+            Some(interval) => advertisement.timestamp - advertisement.timestamp % interval,
+            None => advertisement.timestamp,
+        };
+        if let Some((alias, measurement)) = to_measurement(&advertisement, sensors, discover) {
+            sets.entry(key).or_default().insert(alias, measurement);
+        }
+    }
+
+    sets
+}
+
+/// Returns the alias and the measurement of an advertisement of one of the given sensors, or of
+/// any RuuviTag if `discover` is true.
+fn to_measurement(
+    advertisement: &Advertisement,
+    sensors: &HashMap<String, Sensor>,
+    discover: bool,
+) -> Option<(String, Measurement)> {
+    let (alias, metadata) = match sensors.get(&advertisement.address) {
+        Some(sensor) => (sensor.alias.clone(), sensor.metadata.clone()),
+        None if discover => (advertisement.address.clone(), BTreeMap::new()),
+        None => return None,
+    };
+    let values = to_sensor_value(advertisement).ok()?;
+    let mut measurement = Measurement::new(advertisement, values);
+    measurement.metadata = metadata;
+    Some((alias, measurement))
+}
+
+fn to_sensor_value(advertisement: &Advertisement) -> Result<SensorValues, ParseError> {
+    from_manufacturer_data(&advertisement.manufacturer_data)
 }
 
 fn from_manufacturer_data(data: &[u8]) -> Result<SensorValues, ParseError> {
@@ -361,6 +1918,7 @@ fn from_manufacturer_data(data: &[u8]) -> Result<SensorValues, ParseError> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scanner::MemoryScanner;
     use assert_fs::prelude::*;
 
     #[test]
@@ -383,4 +1941,647 @@ mod tests {
 
         assert_eq!(files, vec!["1234.json", "1235.json", "1236.json"]);
     }
+
+    #[test]
+    fn test_write_cache_entry() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+
+        let measurements = kitchen_measurements();
+
+        let first = write_cache_entry(test_dir.path(), &measurements).unwrap();
+        let second = write_cache_entry(test_dir.path(), &measurements).unwrap();
+
+        // Entries written within the same second must not overwrite each other.
+        assert_eq!(
+            find_cached_measurements(test_dir.path()).unwrap(),
+            vec![first.clone(), second]
+        );
+        assert_eq!(fs::read_dir(test_dir.path()).unwrap().count(), 2);
+
+        let cached = read_cache_entry(test_dir.path(), &first).unwrap().unwrap();
+        assert_eq!(cached["kitchen"].address, "AA:AA:AA:AA:AA:AA");
+
+        // Naming an entry draws 32 bits from the thread RNG, and retrying an upload afterwards
+        // draws 64 bits for the jitter. This used to hit a misaligned read in rand_core 0.4.0.
+        cache_entry_name();
+        let jitter = rand::random::<f64>();
+        assert!((0.0..1.0).contains(&jitter));
+    }
+
+    /// Records the number of sets in each send.
+    #[derive(Default)]
+    struct RecordingSink {
+        sends: std::cell::RefCell<Vec<usize>>,
+    }
+
+    impl Sink for RecordingSink {
+        fn send(&self, _: &HashMap<String, Measurement>) -> Result<(), Error> {
+            self.sends.borrow_mut().push(1);
+            Ok(())
+        }
+
+        fn send_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error> {
+            self.sends.borrow_mut().push(batch.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_send_cached_measurements_skips_corrupt() {
+        let batches = [
+            None,
+            Some(Batch {
+                size: 10,
+                bytes: u64::MAX,
+            }),
+        ];
+        for batch in &batches {
+            let test_dir = assert_fs::TempDir::new().unwrap();
+            test_dir.child("1.json").write_str("{}").unwrap();
+            test_dir.child("2.json").write_str("{\"kitchen\":").unwrap();
+            test_dir.child("3.json").write_str("{}").unwrap();
+
+            let sink = RecordingSink::default();
+            send_cached_measurements(&sink, test_dir.path(), batch.as_ref()).unwrap();
+
+            let expected = if batch.is_some() { vec![2] } else { vec![1, 1] };
+            assert_eq!(*sink.sends.borrow(), expected);
+            assert!(find_cached_measurements(test_dir.path())
+                .unwrap()
+                .is_empty());
+            assert!(test_dir.child("corrupt/2.json").path().is_file());
+        }
+    }
+
+    #[test]
+    fn test_evict_cache_entries() {
+        let now = UNIX_EPOCH + Duration::from_secs(100);
+
+        // Ten entries of 10 bytes cached 10 seconds apart, returned by the time they were cached.
+        let evict = |limits: CacheLimits| -> (Vec<u64>, Vec<u64>) {
+            let entries = (0..10)
+                .map(|i| CacheEntry {
+                    path: PathBuf::from(format!("{}.json", i * 10)),
+                    created: UNIX_EPOCH + Duration::from_secs(i * 10),
+                    bytes: 10,
+                })
+                .collect();
+            let times = |entries: Vec<CacheEntry>| {
+                let mut times: Vec<u64> = entries
+                    .iter()
+                    .map(|entry| entry.created.duration_since(UNIX_EPOCH).unwrap().as_secs())
+                    .collect();
+                times.sort();
+                times
+            };
+            let (expired, evicted) = evict_cache_entries(entries, &limits, now);
+            (times(expired), times(evicted))
+        };
+
+        let no_limits = CacheLimits {
+            entries: None,
+            bytes: None,
+            age: None,
+            thin_interval: None,
+        };
+
+        assert_eq!(
+            evict(CacheLimits {
+                entries: Some(10),
+                ..no_limits
+            }),
+            (vec![], vec![])
+        );
+        assert_eq!(
+            evict(CacheLimits {
+                age: Some(Duration::from_secs(55)),
+                entries: Some(3),
+                ..no_limits
+            }),
+            (vec![0, 10, 20, 30, 40], vec![50, 60])
+        );
+        assert_eq!(
+            evict(CacheLimits {
+                bytes: Some(45),
+                ..no_limits
+            }),
+            (vec![], vec![0, 10, 20, 30, 40, 50])
+        );
+        // Thinned out to 0, 30, 60 and 90, and then the oldest is discarded.
+        assert_eq!(
+            evict(CacheLimits {
+                entries: Some(3),
+                thin_interval: Some(Duration::from_secs(30)),
+                ..no_limits
+            }),
+            (vec![], vec![0, 10, 20, 40, 50, 70, 80])
+        );
+    }
+
+    #[test]
+    fn test_remove_stale_temp_files() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        test_dir.child("1.json").touch().unwrap();
+        test_dir.child(".2.json.tmp").touch().unwrap();
+
+        remove_stale_temp_files(test_dir.path(), SystemTime::now()).unwrap();
+        assert!(test_dir.child(".2.json.tmp").path().exists());
+
+        let later = SystemTime::now() + STALE_TEMP_FILE_AGE * 2;
+        remove_stale_temp_files(test_dir.path(), later).unwrap();
+        assert!(!test_dir.child(".2.json.tmp").path().exists());
+        assert!(test_dir.child("1.json").path().exists());
+    }
+
+    #[test]
+    fn test_limit_cache_corrupt_entries() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        for name in &["1.json", "2.json", "corrupt/a.json", "corrupt/b.json"] {
+            test_dir.child(name).write_str(&"x".repeat(10)).unwrap();
+        }
+
+        let limits = CacheLimits {
+            entries: None,
+            bytes: Some(35),
+            age: None,
+            thin_interval: None,
+        };
+
+        // The cached measurements take 20 bytes, which leaves room for one corrupt entry.
+        limit_cache(test_dir.path(), &limits, SystemTime::now()).unwrap();
+        assert_eq!(find_cached_measurements(test_dir.path()).unwrap().len(), 2);
+        assert!(!test_dir.child("corrupt/a.json").path().exists());
+        assert!(test_dir.child("corrupt/b.json").path().exists());
+
+        let limits = CacheLimits {
+            bytes: None,
+            age: Some(Duration::from_secs(60)),
+            ..limits
+        };
+
+        let later = SystemTime::now() + Duration::from_secs(120);
+        limit_cache(test_dir.path(), &limits, later).unwrap();
+        assert!(find_cached_measurements(test_dir.path())
+            .unwrap()
+            .is_empty());
+        assert!(!test_dir.child("corrupt/b.json").path().exists());
+    }
+
+    #[test]
+    fn test_cache_entry_path() {
+        let cache_dir = Path::new("/cache");
+
+        assert_eq!(
+            cache_entry_path(cache_dir, "1554300000.json").unwrap(),
+            PathBuf::from("/cache/1554300000.json")
+        );
+        assert!(cache_entry_path(cache_dir, "/etc/passwd").is_err());
+        assert!(cache_entry_path(cache_dir, "../config.toml").is_err());
+        assert!(cache_entry_path(cache_dir, "corrupt/1.json").is_err());
+        assert!(cache_entry_path(cache_dir, "..").is_err());
+        assert!(cache_entry_path(cache_dir, "").is_err());
+    }
+
+    #[test]
+    fn test_purge_cache() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        test_dir.child("1.json").write_str("{}").unwrap();
+        test_dir.child("2.json").write_str("{}").unwrap();
+
+        let now = SystemTime::now();
+        let age = Duration::from_secs(3600);
+
+        assert_eq!(purge_cache(test_dir.path(), age, now).unwrap(), 0);
+        assert_eq!(find_cached_measurements(test_dir.path()).unwrap().len(), 2);
+
+        assert_eq!(purge_cache(test_dir.path(), age, now + age * 2).unwrap(), 2);
+        assert!(find_cached_measurements(test_dir.path())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_export_cache() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        let cache_dir = test_dir.child("cache");
+
+        let measurements = kitchen_measurements();
+        write_cache_entry(cache_dir.path(), &measurements).unwrap();
+        write_cache_entry(cache_dir.path(), &measurements).unwrap();
+        cache_dir
+            .child("0.json")
+            .write_str("{\"kitchen\":")
+            .unwrap();
+
+        let output = test_dir.child("export.csv");
+        export_cache(cache_dir.path(), Format::Csv, Some(output.path())).unwrap();
+
+        let contents = fs::read_to_string(output.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("alias,address,timestamp,"));
+        assert!(lines[1].starts_with("kitchen,AA:AA:AA:AA:AA:AA,1554300000,"));
+        assert_eq!(lines[1], lines[2]);
+
+        // Exporting reads the cache without changing it.
+        assert_eq!(find_cached_measurements(cache_dir.path()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_batch_cached_measurements() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+
+        let sizes = [
+            ("1.json", 40),
+            ("2.json", 40),
+            ("3.json", 100),
+            ("4.json", 10),
+        ];
+        for (name, size) in &sizes {
+            test_dir.child(name).write_str(&"x".repeat(*size)).unwrap();
+        }
+
+        let paths = find_cached_measurements(test_dir.path()).unwrap();
+
+        let names = |batches: Vec<Vec<PathBuf>>| -> Vec<Vec<String>> {
+            batches
+                .iter()
+                .map(|batch| {
+                    batch
+                        .iter()
+                        .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+                        .collect()
+                })
+                .collect()
+        };
+
+        let batch = Batch { size: 3, bytes: 90 };
+        assert_eq!(
+            names(batch_cached_measurements(paths.clone(), &batch).unwrap()),
+            vec![vec!["1.json", "2.json"], vec!["3.json"], vec!["4.json"]]
+        );
+
+        let batch = Batch {
+            size: 2,
+            bytes: u64::MAX,
+        };
+        assert_eq!(
+            names(batch_cached_measurements(paths, &batch).unwrap()),
+            vec![vec!["1.json", "2.json"], vec!["3.json", "4.json"]]
+        );
+    }
+
+    #[test]
+    fn test_check_cache_dir() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        test_dir.child("file").touch().unwrap();
+
+        check_cache_dir(&test_dir.path().join("cache")).unwrap();
+        assert!(test_dir.child("cache").path().is_dir());
+        assert_eq!(
+            fs::read_dir(test_dir.child("cache").path())
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let error = check_cache_dir(&test_dir.path().join("file/cache")).unwrap_err();
+        assert!(error.to_string().contains("is not writable"));
+    }
+
+    #[test]
+    fn test_resolve_cache_dir() {
+        let dir = |path: &str| Some(PathBuf::from(path));
+        let var = Some(OsString::from("/var"));
+
+        assert_eq!(
+            resolve_cache_dir(dir("/arg"), var.clone(), dir("/config")).unwrap(),
+            PathBuf::from("/arg")
+        );
+        assert_eq!(
+            resolve_cache_dir(None, var, dir("/config")).unwrap(),
+            PathBuf::from("/var")
+        );
+        assert_eq!(
+            resolve_cache_dir(None, Some(OsString::new()), dir("/config")).unwrap(),
+            PathBuf::from("/config")
+        );
+        assert_eq!(
+            resolve_cache_dir(None, None, None).ok(),
+            default_cache_dir().ok()
+        );
+    }
+
+    fn sensors(sensors: &[(&str, &str)]) -> HashMap<String, Sensor> {
+        sensors
+            .iter()
+            .map(|(address, alias)| {
+                let sensor = Sensor {
+                    alias: alias.to_string(),
+                    metadata: BTreeMap::new(),
+                };
+                (address.to_string(), sensor)
+            })
+            .collect()
+    }
+
+    /// Manufacturer specific data of a data format 3 (RAWv1) advertisement.
+    const DATA_V3: [u8; 16] = [
+        0x99, 0x04, 0x03, 0x17, 0x01, 0x45, 0x35, 0x58, 0x03, 0xE8, 0x04, 0xE7, 0x05, 0xE6, 0x08,
+        0x86,
+    ];
+
+    fn advertisement(address: &str, manufacturer_data: &[u8]) -> Advertisement {
+        Advertisement {
+            address: address.to_string(),
+            manufacturer_data: manufacturer_data.to_vec(),
+            rssi: Some(-80),
+            tx_power_level: None,
+            timestamp: 1234,
+        }
+    }
+
+    #[test]
+    fn test_collect_measurements() {
+        let mut scanner = MemoryScanner::new(vec![
+            advertisement("AA:AA:AA:AA:AA:AA", &[0x4c, 0x00, 0x02, 0x15]),
+            advertisement("DD:DD:DD:DD:DD:DD", &DATA_V3),
+            advertisement("AA:AA:AA:AA:AA:AA", &DATA_V3),
+        ]);
+
+        let sensors = sensors(&[
+            ("AA:AA:AA:AA:AA:AA", "kitchen"),
+            ("BB:BB:BB:BB:BB:BB", "attic"),
+        ]);
+
+        let measurements = collect_measurements(&mut scanner, &sensors, None, false).unwrap();
+
+        assert_eq!(measurements.len(), 1);
+
+        let measurement = &measurements["kitchen"];
+        assert_eq!(measurement.address, "AA:AA:AA:AA:AA:AA");
+        assert_eq!(measurement.timestamp, 1234);
+        assert_eq!(measurement.temperature, Some(1.69));
+        assert_eq!(measurement.humidity, Some(11.5));
+        assert_eq!(measurement.battery_potential, Some(2.182));
+        assert_eq!(measurement.tx_power, None);
+        assert_eq!(measurement.rssi, Some(-80));
+        assert_eq!(measurement.data_format, Some(3));
+    }
+
+    #[test]
+    fn test_collect_measurements_discover() {
+        let mut scanner = MemoryScanner::new(vec![
+            advertisement("AA:AA:AA:AA:AA:AA", &DATA_V3),
+            advertisement("CC:CC:CC:CC:CC:CC", &[0x4c, 0x00, 0x02, 0x15]),
+            advertisement("DD:DD:DD:DD:DD:DD", &DATA_V3),
+        ]);
+
+        let sensors = sensors(&[("AA:AA:AA:AA:AA:AA", "kitchen")]);
+
+        let measurements = collect_measurements(&mut scanner, &sensors, None, true).unwrap();
+
+        let mut aliases: Vec<&str> = measurements.keys().map(String::as_str).collect();
+        aliases.sort();
+
+        assert_eq!(aliases, vec!["DD:DD:DD:DD:DD:DD", "kitchen"]);
+    }
+
+    #[test]
+    fn test_receive_measurements_keeps_latest() {
+        let mut newer = advertisement("AA:AA:AA:AA:AA:AA", &DATA_V3);
+        newer.timestamp = 1235;
+
+        let mut scanner =
+            MemoryScanner::new(vec![advertisement("AA:AA:AA:AA:AA:AA", &DATA_V3), newer]);
+
+        let sensors = sensors(&[("AA:AA:AA:AA:AA:AA", "kitchen")]);
+
+        let adv_rx = scanner.start().unwrap();
+        let mut measurements = HashMap::new();
+
+        assert!(!receive_measurements(
+            &adv_rx,
+            &sensors,
+            None,
+            false,
+            false,
+            &mut measurements
+        ));
+        assert_eq!(measurements["kitchen"].timestamp, 1235);
+    }
+
+    #[test]
+    fn test_group_replayed_measurements() {
+        let replayed = |timestamps: &[u64]| {
+            let advertisements = timestamps
+                .iter()
+                .map(|timestamp| Advertisement {
+                    timestamp: *timestamp,
+                    ..advertisement("AA:AA:AA:AA:AA:AA", &DATA_V3)
+                })
+                .collect();
+            MemoryScanner::new(advertisements).start().unwrap()
+        };
+
+        let sensors = sensors(&[("AA:AA:AA:AA:AA:AA", "kitchen")]);
+
+        let sets = group_replayed_measurements(
+            &replayed(&[1000, 1000, 1030, 1090]),
+            &sensors,
+            None,
+            false,
+        );
+        assert_eq!(sets.keys().collect::<Vec<_>>(), vec![&1000, &1030, &1090]);
+        assert_eq!(sets[&1030]["kitchen"].timestamp, 1030);
+
+        let sets =
+            group_replayed_measurements(&replayed(&[1000, 1010, 1090]), &sensors, Some(60), false);
+        assert_eq!(sets.keys().collect::<Vec<_>>(), vec![&960, &1080]);
+        assert_eq!(sets[&960]["kitchen"].timestamp, 1010);
+    }
+
+    #[test]
+    fn test_next_deadline() {
+        let start = Instant::now();
+        let interval = Duration::from_secs(60);
+
+        // On schedule, the intervals follow each other without drifting.
+        assert_eq!(
+            next_deadline(start, interval, start + Duration::from_secs(5)),
+            start + interval
+        );
+
+        // Publishing took longer than two intervals.
+        let now = start + Duration::from_secs(150);
+        assert_eq!(next_deadline(start, interval, now), now + interval);
+    }
+
+    #[test]
+    fn test_measurement_from_data_format_5() {
+        let data_v5 = [
+            0x99, 0x04, 0x05, 0x12, 0xFC, 0x53, 0x94, 0xC3, 0x7C, 0x00, 0x04, 0xFF, 0xFC, 0x04,
+            0x0C, 0xAC, 0x36, 0x42, 0x00, 0xCD, 0xCB, 0xB8, 0x33, 0x4C, 0x88, 0x4F,
+        ];
+
+        let advertisement = advertisement("CB:B8:33:4C:88:4F", &data_v5);
+        let values = to_sensor_value(&advertisement).unwrap();
+        let measurement = Measurement::new(&advertisement, values);
+
+        assert_eq!(measurement.temperature, Some(24.3));
+        assert_eq!(measurement.humidity, Some(53.49));
+        assert_eq!(measurement.pressure, Some(100.044));
+        assert_eq!(measurement.battery_potential, Some(2.977));
+        assert_eq!(measurement.acceleration_x, Some(0.004));
+        assert_eq!(measurement.acceleration_y, Some(-0.004));
+        assert_eq!(measurement.acceleration_z, Some(1.036));
+        assert_eq!(measurement.tx_power, Some(4));
+        assert_eq!(measurement.movement_counter, Some(66));
+        assert_eq!(measurement.measurement_sequence_number, Some(205));
+        assert_eq!(measurement.data_format, Some(5));
+    }
+
+    #[test]
+    fn test_build_sensors() {
+        let configured = Config::parse(
+            r#"
+            [[sensors]]
+            address = "aa:aa:aa:aa:aa:aa"
+            alias = "kitchen"
+            metadata = { floor = "1" }
+
+            [[sensors]]
+            address = "BB:BB:BB:BB:BB:BB"
+            "#,
+        )
+        .unwrap()
+        .sensors;
+
+        let sensors = build_sensors(&[], &configured);
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(sensors["AA:AA:AA:AA:AA:AA"].alias, "kitchen");
+        assert_eq!(sensors["AA:AA:AA:AA:AA:AA"].metadata["floor"], "1");
+        assert_eq!(sensors["BB:BB:BB:BB:BB:BB"].alias, "BB:BB:BB:BB:BB:BB");
+
+        let args = vec![
+            "AA:AA:AA:AA:AA:AA".to_string(),
+            "CC:CC:CC:CC:CC:CC=attic".to_string(),
+        ];
+        let sensors = build_sensors(&args, &configured);
+        assert_eq!(sensors.len(), 2);
+        assert_eq!(sensors["AA:AA:AA:AA:AA:AA"].alias, "kitchen");
+        assert_eq!(sensors["AA:AA:AA:AA:AA:AA"].metadata["floor"], "1");
+        assert_eq!(sensors["CC:CC:CC:CC:CC:CC"].alias, "attic");
+        assert!(sensors["CC:CC:CC:CC:CC:CC"].metadata.is_empty());
+    }
+
+    #[test]
+    fn test_format_scan_table() {
+        let mut measurements = kitchen_measurements();
+        let kitchen = measurements.get_mut("kitchen").unwrap();
+        kitchen.humidity = Some(40.0);
+        kitchen.pressure = Some(100.044);
+        kitchen.battery_potential = Some(2.977);
+        kitchen.rssi = Some(-70);
+        kitchen.data_format = Some(5);
+        measurements.insert(
+            "CB:B8:33:4C:88:4F".to_string(),
+            Measurement {
+                address: "CB:B8:33:4C:88:4F".to_string(),
+                data_format: Some(3),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            format_scan_table(&measurements),
+            "ADDRESS            RSSI  FORMAT  TEMPERATURE  HUMIDITY  PRESSURE  BATTERY  ALIAS\n\
+             AA:AA:AA:AA:AA:AA   -70       5        21.50     40.00    100.04    2.977  kitchen\n\
+             CB:B8:33:4C:88:4F     -       3            -         -         -        -  ruuvi-884f\n\
+             \n\
+             AA:AA:AA:AA:AA:AA=kitchen CB:B8:33:4C:88:4F=ruuvi-884f\n"
+        );
+    }
+
+    #[test]
+    fn test_format_cache_table() {
+        let rows = vec![
+            (
+                CacheEntry {
+                    path: PathBuf::from("/cache/1554300000.json"),
+                    created: UNIX_EPOCH + Duration::from_secs(1554300000),
+                    bytes: 314,
+                },
+                Some(2),
+            ),
+            (
+                CacheEntry {
+                    path: PathBuf::from("/cache/1554300060.json"),
+                    created: UNIX_EPOCH + Duration::from_secs(1554300060),
+                    bytes: 4,
+                },
+                None,
+            ),
+        ];
+
+        assert_eq!(
+            format_cache_table(&rows),
+            "    CACHED  SENSORS    BYTES  ENTRY\n\
+             1554300000        2      314  1554300000.json\n\
+             1554300060        -        4  1554300060.json\n"
+        );
+    }
+
+    #[test]
+    fn test_append_measurements() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        let path = test_dir.child("measurements.csv");
+
+        let measurements = kitchen_measurements();
+
+        append_measurements(path.path(), Format::Csv, &measurements).unwrap();
+        append_measurements(path.path(), Format::Csv, &measurements).unwrap();
+
+        let contents = fs::read_to_string(path.path()).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("alias,address,"));
+        assert!(lines[1].starts_with("kitchen,AA:AA:AA:AA:AA:AA,"));
+        assert_eq!(lines[1], lines[2]);
+
+        // Intervals without any sensors seen must not add blank lines.
+        append_measurements(path.path(), Format::Csv, &HashMap::new()).unwrap();
+        assert_eq!(fs::read_to_string(path.path()).unwrap(), contents);
+
+        let path = test_dir.child("measurements.json");
+        append_measurements(path.path(), Format::Json, &HashMap::new()).unwrap();
+        assert_eq!(fs::read_to_string(path.path()).unwrap(), "");
+
+        let path = test_dir.child("empty.csv");
+        append_measurements(path.path(), Format::Csv, &HashMap::new()).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.path()).unwrap(),
+            Format::Csv.header().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_find_missing_sensors() {
+        let sensors = sensors(&[
+            ("AA:AA:AA:AA:AA:AA", "kitchen"),
+            ("BB:BB:BB:BB:BB:BB", "BB:BB:BB:BB:BB:BB"),
+            ("CC:CC:CC:CC:CC:CC", "attic"),
+        ]);
+
+        let measurements = kitchen_measurements();
+
+        assert_eq!(
+            find_missing_sensors(&sensors, &measurements),
+            vec![
+                ("BB:BB:BB:BB:BB:BB", "BB:BB:BB:BB:BB:BB"),
+                ("CC:CC:CC:CC:CC:CC", "attic"),
+            ]
+        );
+    }
 }
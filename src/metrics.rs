@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::Measurement;
+
+/// How long a client may take to send its request or to receive the response.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many connections are served at a time. Further connections wait in the listen backlog
+/// until a worker is free, so idle clients can't pile up threads.
+const WORKERS: usize = 4;
+
+/// A gauge exported for each sensor: metric name, help text and a function that returns the
+/// value of the gauge from a measurement.
+type Gauge = (&'static str, &'static str, fn(&Measurement) -> Option<f64>);
+
+const GAUGES: &[Gauge] = &[
+    (
+        "ruuvitag_temperature_celsius",
+        "Temperature in degrees Celsius.",
+        |m| m.temperature,
+    ),
+    (
+        "ruuvitag_humidity_percent",
+        "Relative humidity in percent.",
+        |m| m.humidity,
+    ),
+    (
+        "ruuvitag_pressure_pascals",
+        "Air pressure in pascals.",
+        |m| m.pressure.map(|x| x * 1000.0),
+    ),
+    (
+        "ruuvitag_battery_volts",
+        "Battery potential in volts.",
+        |m| m.battery_potential,
+    ),
+    (
+        "ruuvitag_last_seen_timestamp_seconds",
+        "Unix timestamp of the latest measurement.",
+        |m| Some(m.timestamp as f64),
+    ),
+];
+
+/// Serves the latest measurement of each sensor in the Prometheus text format at /metrics.
+pub struct MetricsServer {
+    latest: Arc<Mutex<HashMap<String, Measurement>>>,
+    /// The address actually listened on, for tests that listen on port 0.
+    #[cfg(test)]
+    local_addr: std::net::SocketAddr,
+}
+
+impl MetricsServer {
+    /// Starts serving at the given address in a fixed number of background threads.
+    pub fn start(addr: &str) -> Result<MetricsServer, Error> {
+        let listener = TcpListener::bind(addr)
+            .map_err(|error| failure::format_err!("failed to listen on {}: {}", addr, error))?;
+
+        #[cfg(test)]
+        let local_addr = listener.local_addr()?;
+
+        let latest = Arc::new(Mutex::new(HashMap::new()));
+
+        // Each worker accepts connections on its own, so that a slow or idle client holds up
+        // only one of them.
+        for _ in 0..WORKERS {
+            let listener = listener.try_clone()?;
+            let latest = latest.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let result = stream
+                        .map_err(Error::from)
+                        .and_then(|stream| handle_connection(stream, &latest));
+                    if let Err(error) = result {
+                        eprintln!("error: metrics: {}", error);
+                    }
+                }
+            });
+        }
+
+        Ok(MetricsServer {
+            latest,
+            #[cfg(test)]
+            local_addr,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Replaces the latest measurements of the given sensors. Sensors that are not included keep
+    /// their previous measurement, so that their last seen timestamp shows how stale it is.
+    pub fn update(&self, measurements: &HashMap<String, Measurement>) {
+        let mut latest = self.latest.lock().unwrap();
+        for (alias, measurement) in measurements {
+            latest.insert(alias.clone(), measurement.clone());
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    latest: &Mutex<HashMap<String, Measurement>>,
+) -> Result<(), Error> {
+    stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+    stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers, they are not needed.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+
+    let (status, content_type, body) = if method != "GET" {
+        ("405 Method Not Allowed", "text/plain", String::new())
+    } else if path == "/metrics" {
+        let body = render_metrics(&latest.lock().unwrap());
+        ("200 OK", "text/plain; version=0.0.4", body)
+    } else {
+        ("404 Not Found", "text/plain", String::new())
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+/// Renders the measurements in the Prometheus text format, labelled by alias and address.
+fn render_metrics(latest: &HashMap<String, Measurement>) -> String {
+    let mut aliases: Vec<&String> = latest.keys().collect();
+    aliases.sort();
+
+    let mut output = String::new();
+
+    for (name, help, gauge) in GAUGES {
+        output.push_str(&format!("# HELP {} {}\n", name, help));
+        output.push_str(&format!("# TYPE {} gauge\n", name));
+        for alias in &aliases {
+            let measurement = &latest[*alias];
+            if let Some(value) = gauge(measurement) {
+                output.push_str(&format!(
+                    "{}{{alias=\"{}\",address=\"{}\"}} {}\n",
+                    name,
+                    escape_label(alias),
+                    escape_label(&measurement.address),
+                    value
+                ));
+            }
+        }
+    }
+
+    output
+}
+
+fn escape_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitchen_measurements;
+    use std::io::Read;
+
+    #[test]
+    fn test_render_metrics() {
+        let mut measurements = kitchen_measurements();
+        measurements.get_mut("kitchen").unwrap().pressure = Some(100.044);
+
+        let output = render_metrics(&measurements);
+
+        let lines: Vec<&str> = output.lines().filter(|l| !l.starts_with('#')).collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "ruuvitag_temperature_celsius{alias=\"kitchen\",address=\"AA:AA:AA:AA:AA:AA\"} 21.5",
+                "ruuvitag_pressure_pascals{alias=\"kitchen\",address=\"AA:AA:AA:AA:AA:AA\"} 100044",
+                "ruuvitag_last_seen_timestamp_seconds{alias=\"kitchen\",address=\"AA:AA:AA:AA:AA:AA\"} 1554300000",
+            ]
+        );
+        assert!(output.contains("# TYPE ruuvitag_humidity_percent gauge\n"));
+    }
+
+    #[test]
+    fn test_metrics_server() {
+        let server = MetricsServer::start("127.0.0.1:0").unwrap();
+        server.update(&kitchen_measurements());
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("ruuvitag_temperature_celsius{alias=\"kitchen\""));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn test_metrics_server_idle_connection() {
+        let server = MetricsServer::start("127.0.0.1:0").unwrap();
+        server.update(&kitchen_measurements());
+
+        // Connects but never sends a request.
+        let _idle = TcpStream::connect(server.local_addr()).unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT / 2))
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_metrics_server_busy_workers() {
+        let server = MetricsServer::start("127.0.0.1:0").unwrap();
+        server.update(&kitchen_measurements());
+
+        // Keeps every worker busy until the idle connections time out.
+        let _idle: Vec<TcpStream> = (0..WORKERS)
+            .map(|_| TcpStream::connect(server.local_addr()).unwrap())
+            .collect();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream
+            .set_read_timeout(Some(CONNECTION_TIMEOUT * 2))
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+}
@@ -0,0 +1,728 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process;
+use std::slice;
+use std::time::Duration;
+
+use failure::Error;
+
+use crate::config::MqttConfig;
+use crate::Measurement;
+
+/// Topic the measurements are published to if none is configured.
+const DEFAULT_TOPIC: &str = "ruuvitag/{alias}";
+
+/// Topic prefix of the Home Assistant discovery messages if none is configured.
+const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// A quantity announced to Home Assistant as a sensor entity of its own.
+struct Quantity {
+    /// Object id of the entity, unique within the tag.
+    object_id: &'static str,
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+    /// The field of the measurement holding the value.
+    field: &'static str,
+    /// Whether the entity is diagnostic rather than a measurement of the environment.
+    diagnostic: bool,
+}
+
+const QUANTITIES: &[Quantity] = &[
+    Quantity {
+        object_id: "temperature",
+        name: "Temperature",
+        device_class: "temperature",
+        unit: "°C",
+        field: "temperature",
+        diagnostic: false,
+    },
+    Quantity {
+        object_id: "humidity",
+        name: "Humidity",
+        device_class: "humidity",
+        unit: "%",
+        field: "humidity",
+        diagnostic: false,
+    },
+    Quantity {
+        object_id: "pressure",
+        name: "Pressure",
+        device_class: "pressure",
+        unit: "kPa",
+        field: "pressure",
+        diagnostic: false,
+    },
+    Quantity {
+        object_id: "battery",
+        name: "Battery",
+        device_class: "voltage",
+        unit: "V",
+        field: "battery_potential",
+        diagnostic: true,
+    },
+    Quantity {
+        object_id: "rssi",
+        name: "Signal strength",
+        device_class: "signal_strength",
+        unit: "dBm",
+        field: "rssi",
+        diagnostic: true,
+    },
+];
+
+/// How long to wait for the broker to accept a connection or to respond to a packet.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Keep alive interval sent to the broker, seconds. The connection is only kept open for as
+/// long as it takes to publish a set of measurements.
+const KEEP_ALIVE: u16 = 60;
+
+// The first byte of each control packet: the packet type and its flags.
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const DISCONNECT: u8 = 0xE0;
+
+/// A message to publish.
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// Publishes measurements to an MQTT broker using MQTT 3.1.1. A new connection is made for each
+/// set of measurements.
+pub struct MqttPublisher {
+    host: String,
+    port: u16,
+    tls: bool,
+    /// CA certificate used to verify the broker, in addition to the system certificates.
+    ca_file: Option<PathBuf>,
+    /// Topic template, `{alias}` and `{address}` are replaced with those of the sensor.
+    topic: String,
+    qos: u8,
+    retain: bool,
+    username: Option<String>,
+    password: Option<String>,
+    /// Topic prefix of the Home Assistant discovery messages, if they are published.
+    discovery_prefix: Option<String>,
+    /// Unique ids of the Home Assistant entities that have been announced.
+    announced: RefCell<HashSet<String>>,
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+impl MqttPublisher {
+    /// Creates a publisher for the broker at `url`, mqtt://HOST[:PORT] or mqtts://HOST[:PORT]
+    /// for TLS. The rest of the settings are taken from the configuration.
+    pub fn new(url: &str, config: &MqttConfig) -> Result<MqttPublisher, Error> {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|error| failure::format_err!("invalid MQTT broker {}: {}", url, error))?;
+
+        let tls = match parsed.scheme() {
+            "mqtt" => false,
+            "mqtts" => true,
+            _ => {
+                return Err(failure::format_err!(
+                    "invalid MQTT broker {}: expected mqtt:// or mqtts://",
+                    url
+                ));
+            }
+        };
+
+        let host = match parsed.host_str() {
+            Some(host) if !host.is_empty() => host.to_string(),
+            _ => return Err(failure::format_err!("invalid MQTT broker {}: no host", url)),
+        };
+
+        let port = parsed.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+        let qos = config.qos.unwrap_or(0);
+        if qos > 2 {
+            return Err(failure::format_err!(
+                "invalid MQTT QoS {}: expected 0, 1 or 2",
+                qos
+            ));
+        }
+
+        if config.password.is_some() && config.username.is_none() {
+            return Err(failure::format_err!(
+                "MQTT password given without a username"
+            ));
+        }
+
+        Ok(MqttPublisher {
+            host,
+            port,
+            tls,
+            ca_file: config.ca_file.clone(),
+            topic: config
+                .topic
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TOPIC.to_string()),
+            qos,
+            retain: config.retain.unwrap_or(false),
+            username: config.username.clone(),
+            password: match config.password {
+                Some(ref password) => Some(password.read()?),
+                None => None,
+            },
+            discovery_prefix: if config.discovery.unwrap_or(false) {
+                Some(
+                    config
+                        .discovery_prefix
+                        .clone()
+                        .unwrap_or_else(|| DEFAULT_DISCOVERY_PREFIX.to_string()),
+                )
+            } else {
+                None
+            },
+            announced: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// Returns the topic of a sensor.
+    pub fn topic(&self, alias: &str, address: &str) -> String {
+        self.topic
+            .replace("{alias}", alias)
+            .replace("{address}", address)
+    }
+
+    /// Publishes each measurement as a JSON object to the topic of its sensor. If Home Assistant
+    /// discovery is enabled, the entities of the sensor are announced before its first
+    /// measurement.
+    pub fn publish_measurements(
+        &self,
+        measurements: &HashMap<String, Measurement>,
+    ) -> Result<(), Error> {
+        self.publish_batch(slice::from_ref(measurements))
+    }
+
+    /// Publishes several sets of measurements using a single connection.
+    pub fn publish_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error> {
+        let (messages, announced) = self.messages(batch)?;
+
+        self.publish(&messages)?;
+
+        self.announced.borrow_mut().extend(announced);
+
+        Ok(())
+    }
+
+    /// Returns the messages to publish for the sets of measurements and the unique ids of the
+    /// Home Assistant entities announced by them.
+    fn messages(
+        &self,
+        batch: &[HashMap<String, Measurement>],
+    ) -> Result<(Vec<Message>, Vec<String>), Error> {
+        let mut messages = Vec::new();
+        let mut announced = Vec::new();
+
+        for measurements in batch {
+            let mut aliases: Vec<&String> = measurements.keys().collect();
+            aliases.sort();
+
+            for alias in aliases {
+                let measurement = &measurements[alias];
+                let topic = self.topic(alias, &measurement.address);
+
+                if let Some(ref prefix) = self.discovery_prefix {
+                    let values = serde_json::to_value(measurement)?;
+                    let node_id = format!(
+                        "ruuvitag_{}",
+                        measurement.address.replace(':', "").to_lowercase()
+                    );
+
+                    // Only the quantities the sensor broadcasts are announced.
+                    for quantity in QUANTITIES.iter().filter(|q| !values[q.field].is_null()) {
+                        let unique_id = format!("{}_{}", node_id, quantity.object_id);
+                        if self.announced.borrow().contains(&unique_id)
+                            || announced.contains(&unique_id)
+                        {
+                            continue;
+                        }
+
+                        let mut config = serde_json::json!({
+                            "name": quantity.name,
+                            "unique_id": unique_id,
+                            "state_topic": topic,
+                            "value_template": format!("{{{{ value_json.{} }}}}", quantity.field),
+                            "device_class": quantity.device_class,
+                            "unit_of_measurement": quantity.unit,
+                            "state_class": "measurement",
+                            "device": {
+                                "identifiers": [node_id],
+                                "name": alias,
+                                "manufacturer": "Ruuvi Innovations",
+                                "model": "RuuviTag",
+                            },
+                        });
+                        if quantity.diagnostic {
+                            config["entity_category"] = "diagnostic".into();
+                        }
+
+                        messages.push(Message {
+                            topic: format!(
+                                "{}/sensor/{}/{}/config",
+                                prefix, node_id, quantity.object_id
+                            ),
+                            payload: config.to_string().into_bytes(),
+                            retain: true,
+                        });
+                        announced.push(unique_id);
+                    }
+                }
+
+                messages.push(Message {
+                    topic,
+                    payload: serde_json::to_vec(measurement)?,
+                    retain: self.retain,
+                });
+            }
+        }
+
+        Ok((messages, announced))
+    }
+
+    /// Connects to the broker, publishes the messages and disconnects. With QoS 1 and 2 each
+    /// message is acknowledged by the broker before the next one is published.
+    pub fn publish(&self, messages: &[Message]) -> Result<(), Error> {
+        let mut stream = self.connect()?;
+
+        for (index, message) in messages.iter().enumerate() {
+            // Packet identifiers must be non-zero.
+            let packet_id = (index % usize::from(u16::MAX)) as u16 + 1;
+            publish(stream.as_mut(), message, self.qos, packet_id)?;
+        }
+
+        write_packet(stream.as_mut(), DISCONNECT, &[])?;
+
+        Ok(())
+    }
+
+    fn connect(&self) -> Result<Box<dyn Stream>, Error> {
+        let broker = format!("{}:{}", self.host, self.port);
+
+        let tcp = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .filter_map(|addr| TcpStream::connect_timeout(&addr, TIMEOUT).ok())
+            .next()
+            .ok_or_else(|| failure::format_err!("failed to connect to MQTT broker {}", broker))?;
+
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut stream: Box<dyn Stream> = if self.tls {
+            let mut builder = native_tls::TlsConnector::builder();
+            if let Some(ref path) = self.ca_file {
+                let pem = fs::read(path).map_err(|error| {
+                    failure::format_err!("failed to read {}: {}", path.display(), error)
+                })?;
+                builder.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+            }
+            let connector = builder.build()?;
+            Box::new(connector.connect(&self.host, tcp).map_err(|error| {
+                failure::format_err!(
+                    "TLS handshake with MQTT broker {} failed: {}",
+                    broker,
+                    error
+                )
+            })?)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut flags = 0x02; // Clean session.
+        let mut payload = Vec::new();
+        push_string(
+            &mut payload,
+            format!("ruuvitag-upload-{}", process::id()).as_bytes(),
+        );
+        if let Some(ref username) = self.username {
+            flags |= 0x80;
+            push_string(&mut payload, username.as_bytes());
+        }
+        if let Some(ref password) = self.password {
+            flags |= 0x40;
+            push_string(&mut payload, password.as_bytes());
+        }
+
+        let mut body = Vec::new();
+        push_string(&mut body, b"MQTT");
+        body.push(4); // Protocol level of MQTT 3.1.1.
+        body.push(flags);
+        body.extend_from_slice(&KEEP_ALIVE.to_be_bytes());
+        body.extend_from_slice(&payload);
+
+        write_packet(stream.as_mut(), CONNECT, &body)?;
+
+        let (header, body) = read_packet(stream.as_mut())?;
+        if header != CONNACK || body.len() != 2 {
+            return Err(failure::format_err!(
+                "unexpected response from MQTT broker {}",
+                broker
+            ));
+        }
+
+        let reason = match body[1] {
+            0 => return Ok(stream),
+            1 => "unacceptable protocol version",
+            2 => "client identifier rejected",
+            3 => "server unavailable",
+            4 => "bad username or password",
+            5 => "not authorized",
+            _ => "unknown error",
+        };
+
+        Err(failure::format_err!(
+            "MQTT broker {} refused the connection: {}",
+            broker,
+            reason
+        ))
+    }
+}
+
+fn publish(
+    stream: &mut dyn Stream,
+    message: &Message,
+    qos: u8,
+    packet_id: u16,
+) -> Result<(), Error> {
+    let mut body = Vec::new();
+    push_string(&mut body, message.topic.as_bytes());
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(&message.payload);
+
+    let header = PUBLISH | (qos << 1) | u8::from(message.retain);
+
+    write_packet(stream, header, &body)?;
+
+    match qos {
+        1 => expect_ack(stream, PUBACK, packet_id),
+        2 => {
+            expect_ack(stream, PUBREC, packet_id)?;
+            write_packet(stream, PUBREL, &packet_id.to_be_bytes())?;
+            expect_ack(stream, PUBCOMP, packet_id)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn expect_ack(stream: &mut dyn Stream, packet_type: u8, packet_id: u16) -> Result<(), Error> {
+    let (header, body) = read_packet(stream)?;
+    if header != packet_type || body != packet_id.to_be_bytes() {
+        return Err(failure::format_err!("unexpected response from MQTT broker"));
+    }
+    Ok(())
+}
+
+/// Writes a control packet, encoding the length of the body in the fixed header.
+fn write_packet(stream: &mut dyn Write, header: u8, body: &[u8]) -> Result<(), Error> {
+    let mut packet = vec![header];
+
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+
+    packet.extend_from_slice(body);
+
+    stream.write_all(&packet)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+/// Reads a control packet. Returns the first byte of the packet and the body.
+fn read_packet(stream: &mut dyn Read) -> Result<(u8, Vec<u8>), Error> {
+    let mut byte = [0];
+
+    stream.read_exact(&mut byte)?;
+    let header = byte[0];
+
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        stream.read_exact(&mut byte)?;
+        length |= usize::from(byte[0] & 0x7F) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift > 21 {
+            return Err(failure::format_err!("malformed MQTT packet"));
+        }
+    }
+
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body)?;
+
+    Ok((header, body))
+}
+
+/// Appends a length-prefixed string.
+fn push_string(buf: &mut Vec<u8>, s: &[u8]) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Secret;
+    use crate::kitchen_measurements;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn config(topic: &str, qos: u8) -> MqttConfig {
+        MqttConfig {
+            topic: Some(topic.to_string()),
+            qos: Some(qos),
+            retain: Some(true),
+            username: Some("user".to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Accepts one connection, answers the CONNECT with the given return code and acknowledges
+    /// every packet like a broker would. Returns the packets received from the client.
+    fn broker(listener: TcpListener, return_code: u8) -> thread::JoinHandle<Vec<(u8, Vec<u8>)>> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut packets = Vec::new();
+            loop {
+                let (header, body) = read_packet(&mut stream).unwrap();
+                let id = |offset: usize| body[offset..offset + 2].to_vec();
+                match header & 0xF0 {
+                    CONNECT => write_packet(&mut stream, CONNACK, &[0, return_code]).unwrap(),
+                    PUBLISH if header & 0x06 == 0x02 => {
+                        let id = id(2 + usize::from(body[1]));
+                        write_packet(&mut stream, PUBACK, &id).unwrap();
+                    }
+                    PUBLISH if header & 0x06 == 0x04 => {
+                        let id = id(2 + usize::from(body[1]));
+                        write_packet(&mut stream, PUBREC, &id).unwrap();
+                    }
+                    _ if header == PUBREL => write_packet(&mut stream, PUBCOMP, &id(0)).unwrap(),
+                    _ => {}
+                }
+                let done = header == DISCONNECT || (header == CONNECT && return_code != 0);
+                packets.push((header, body));
+                if done {
+                    return packets;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_publish_measurements() {
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        let password = test_dir.path().join("password");
+        fs::write(&password, "secret\n").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let broker = broker(listener, 0);
+
+        let config = MqttConfig {
+            password: Some(Secret::File(password)),
+            ..config("home/{alias}/{address}", 1)
+        };
+        let publisher = MqttPublisher::new(&url, &config).unwrap();
+        publisher
+            .publish_measurements(&kitchen_measurements())
+            .unwrap();
+
+        let packets = broker.join().unwrap();
+        assert_eq!(packets.len(), 3);
+
+        let (header, ref body) = packets[0];
+        assert_eq!(header, CONNECT);
+        assert_eq!(body[7], 0xC2);
+        assert!(body.ends_with(b"\x00\x04user\x00\x06secret"));
+
+        let (header, ref body) = packets[1];
+        assert_eq!(header, PUBLISH | 0x02 | 0x01);
+        let topic = b"home/kitchen/AA:AA:AA:AA:AA:AA";
+        assert_eq!(&body[2..2 + topic.len()], &topic[..]);
+        let payload: serde_json::Value = serde_json::from_slice(&body[4 + topic.len()..]).unwrap();
+        assert_eq!(payload["temperature"], 21.5);
+        assert_eq!(payload["address"], "AA:AA:AA:AA:AA:AA");
+
+        assert_eq!(packets[2].0, DISCONNECT);
+    }
+
+    #[test]
+    fn test_publish_qos_2() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let broker = broker(listener, 0);
+
+        let publisher = MqttPublisher::new(&url, &config("ruuvitag/{alias}", 2)).unwrap();
+        publisher
+            .publish_batch(&[kitchen_measurements(), kitchen_measurements()])
+            .unwrap();
+
+        let packets = broker.join().unwrap();
+        let headers: Vec<u8> = packets.iter().map(|(header, _)| *header).collect();
+        assert_eq!(
+            headers,
+            vec![
+                CONNECT,
+                PUBLISH | 0x04 | 0x01,
+                PUBREL,
+                PUBLISH | 0x04 | 0x01,
+                PUBREL,
+                DISCONNECT
+            ]
+        );
+        assert_eq!(packets[2].1, vec![0, 1]);
+        assert_eq!(packets[4].1, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_connection_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let broker = broker(listener, 4);
+
+        let publisher = MqttPublisher::new(&url, &config("ruuvitag/{alias}", 0)).unwrap();
+        let error = publisher
+            .publish_measurements(&kitchen_measurements())
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("refused the connection: bad username or password"));
+
+        // Nothing is published after the refusal.
+        assert_eq!(broker.join().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_packet_length() {
+        for (length, encoded) in &[
+            (0, &[0x00][..]),
+            (127, &[0x7F][..]),
+            (128, &[0x80, 0x01][..]),
+            (16383, &[0xFF, 0x7F][..]),
+            (16384, &[0x80, 0x80, 0x01][..]),
+            (2097152, &[0x80, 0x80, 0x80, 0x01][..]),
+        ] {
+            let body = vec![0xAB; *length];
+
+            let mut packet = Vec::new();
+            write_packet(&mut packet, PUBLISH, &body).unwrap();
+            assert_eq!(&packet[1..1 + encoded.len()], *encoded);
+
+            let (header, read) = read_packet(&mut packet.as_slice()).unwrap();
+            assert_eq!(header, PUBLISH);
+            assert_eq!(read, body);
+        }
+
+        // The remaining length is at most four bytes.
+        let mut malformed: &[u8] = &[PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01];
+        assert!(read_packet(&mut malformed).is_err());
+    }
+
+    #[test]
+    fn test_new() {
+        let config = MqttConfig::default();
+
+        let publisher = MqttPublisher::new("mqtts://broker.local", &config).unwrap();
+        assert!(publisher.tls);
+        assert_eq!(publisher.port, 8883);
+        assert_eq!(
+            publisher.topic("kitchen", "AA:AA:AA:AA:AA:AA"),
+            "ruuvitag/kitchen"
+        );
+
+        assert!(publisher.discovery_prefix.is_none());
+
+        assert!(MqttPublisher::new("http://broker.local", &config).is_err());
+        assert!(MqttPublisher::new("mqtt://broker.local", &self::config("x", 3)).is_err());
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let config = MqttConfig {
+            discovery: Some(true),
+            ..Default::default()
+        };
+        let publisher = MqttPublisher::new("mqtt://broker.local", &config).unwrap();
+
+        let mut measurements = HashMap::new();
+        measurements.insert(
+            "kitchen".to_string(),
+            Measurement {
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                temperature: Some(21.5),
+                battery_potential: Some(2.977),
+                ..Default::default()
+            },
+        );
+
+        let (messages, announced) = publisher.messages(slice::from_ref(&measurements)).unwrap();
+
+        let topics: Vec<&str> = messages.iter().map(|m| m.topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            vec![
+                "homeassistant/sensor/ruuvitag_aabbccddeeff/temperature/config",
+                "homeassistant/sensor/ruuvitag_aabbccddeeff/battery/config",
+                "ruuvitag/kitchen",
+            ]
+        );
+        assert!(messages[0].retain);
+        assert!(!messages[2].retain);
+        assert_eq!(
+            announced,
+            vec![
+                "ruuvitag_aabbccddeeff_temperature",
+                "ruuvitag_aabbccddeeff_battery"
+            ]
+        );
+
+        let config: serde_json::Value = serde_json::from_slice(&messages[1].payload).unwrap();
+        assert_eq!(config["state_topic"], "ruuvitag/kitchen");
+        assert_eq!(
+            config["value_template"],
+            "{{ value_json.battery_potential }}"
+        );
+        assert_eq!(config["device_class"], "voltage");
+        assert_eq!(config["unit_of_measurement"], "V");
+        assert_eq!(config["entity_category"], "diagnostic");
+        assert_eq!(config["device"]["name"], "kitchen");
+
+        // Each entity is announced once per batch.
+        let (messages, _) = publisher
+            .messages(&[measurements.clone(), measurements.clone()])
+            .unwrap();
+        assert_eq!(messages.len(), 4);
+
+        publisher.announced.borrow_mut().extend(announced);
+        let (messages, _) = publisher.messages(slice::from_ref(&measurements)).unwrap();
+        assert_eq!(messages.len(), 1);
+    }
+}
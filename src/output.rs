@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::Measurement;
+
+/// Name of the InfluxDB measurement the sensor values are written to.
+const INFLUX_MEASUREMENT: &str = "ruuvitag";
+
+/// How a set of measurements is rendered for stdout and uploading.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A JSON object keyed by alias.
+    #[default]
+    Json,
+    /// InfluxDB line protocol, one line per alias.
+    Influx,
+    /// CSV with a header row, one row per alias.
+    Csv,
+}
+
+impl Format {
+    pub fn render(self, measurements: &HashMap<String, Measurement>) -> String {
+        let mut output = self.header().unwrap_or_default();
+        output.push_str(&self.render_records(measurements));
+        output
+    }
+
+    /// Returns the header that precedes the records, if the format has one.
+    pub fn header(self) -> Option<String> {
+        match self {
+            Format::Csv => Some(format!("{}\n", CSV_COLUMNS.join(","))),
+            _ => None,
+        }
+    }
+
+    /// Renders several sets of measurements as one document: a JSON array of the sets, or the
+    /// records of every set after a single header.
+    pub fn render_batch(self, batch: &[HashMap<String, Measurement>]) -> String {
+        let records: Vec<String> = batch
+            .iter()
+            .map(|measurements| self.render_records(measurements))
+            .collect();
+        match self {
+            Format::Json => format!("[{}]", records.join(",")),
+            _ => self.header().unwrap_or_default() + &records.concat(),
+        }
+    }
+
+    /// Renders the measurements without the header.
+    pub fn render_records(self, measurements: &HashMap<String, Measurement>) -> String {
+        match self {
+            Format::Json => serde_json::to_string(measurements).unwrap(),
+            Format::Influx => render_influx(measurements),
+            Format::Csv => render_csv(measurements),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Influx => "text/plain; charset=utf-8",
+            Format::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    /// Adjusts the upload URL for the format. The timestamps of the line protocol are in
+    /// seconds, so InfluxDB has to be told about it.
+    pub fn upload_url(self, url: &str) -> Result<reqwest::Url, reqwest::UrlError> {
+        let mut url = reqwest::Url::parse(url)?;
+        if self == Format::Influx && !url.query_pairs().any(|(key, _)| key == "precision") {
+            url.query_pairs_mut().append_pair("precision", "s");
+        }
+        Ok(url)
+    }
+}
+
+/// Renders the measurements as InfluxDB line protocol, sorted by alias. The alias, the address
+/// and the metadata of the sensor are written as tags and the sensor values as fields.
+fn render_influx(measurements: &HashMap<String, Measurement>) -> String {
+    let mut aliases: Vec<&String> = measurements.keys().collect();
+    aliases.sort();
+
+    let mut lines = String::new();
+
+    for alias in aliases {
+        let measurement = &measurements[alias];
+
+        let mut tags = vec![
+            ("alias", alias.as_str()),
+            ("address", measurement.address.as_str()),
+        ];
+        tags.extend(
+            measurement
+                .metadata
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        );
+
+        let float = |value: Option<f64>| value.map(|value| value.to_string());
+        let integer = |value: Option<i64>| value.map(|value| format!("{}i", value));
+
+        let fields = vec![
+            ("humidity", float(measurement.humidity)),
+            ("temperature", float(measurement.temperature)),
+            ("pressure", float(measurement.pressure)),
+            ("battery_potential", float(measurement.battery_potential)),
+            ("acceleration_x", float(measurement.acceleration_x)),
+            ("acceleration_y", float(measurement.acceleration_y)),
+            ("acceleration_z", float(measurement.acceleration_z)),
+            ("tx_power", integer(measurement.tx_power.map(i64::from))),
+            (
+                "movement_counter",
+                integer(measurement.movement_counter.map(i64::from)),
+            ),
+            (
+                "measurement_sequence_number",
+                integer(measurement.measurement_sequence_number.map(i64::from)),
+            ),
+            ("rssi", integer(measurement.rssi.map(i64::from))),
+            (
+                "data_format",
+                integer(measurement.data_format.map(i64::from)),
+            ),
+        ];
+
+        let fields: Vec<String> = fields
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| format!("{}={}", key, value)))
+            .collect();
+
+        // A point without fields is not valid line protocol.
+        if fields.is_empty() {
+            continue;
+        }
+
+        lines.push_str(INFLUX_MEASUREMENT);
+        for (key, value) in tags {
+            lines.push_str(&format!(",{}={}", escape_influx(key), escape_influx(value)));
+        }
+        lines.push_str(&format!(
+            " {} {}\n",
+            fields.join(","),
+            measurement.timestamp
+        ));
+    }
+
+    lines
+}
+
+/// The columns of the CSV format. The order must not change, as files are appended to.
+const CSV_COLUMNS: &[&str] = &[
+    "alias",
+    "address",
+    "timestamp",
+    "humidity",
+    "temperature",
+    "pressure",
+    "battery_potential",
+    "acceleration_x",
+    "acceleration_y",
+    "acceleration_z",
+    "tx_power",
+    "movement_counter",
+    "measurement_sequence_number",
+    "rssi",
+    "data_format",
+    "metadata",
+];
+
+/// Renders the measurements as CSV rows, sorted by alias. Missing values are left empty. The
+/// metadata is written to a single column as `key=value` pairs separated by semicolons.
+fn render_csv(measurements: &HashMap<String, Measurement>) -> String {
+    fn value<T: ToString>(value: Option<T>) -> String {
+        value.map(|value| value.to_string()).unwrap_or_default()
+    }
+
+    let mut aliases: Vec<&String> = measurements.keys().collect();
+    aliases.sort();
+
+    let mut rows = String::new();
+
+    for alias in aliases {
+        let measurement = &measurements[alias];
+
+        let metadata: Vec<String> = measurement
+            .metadata
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        let row = vec![
+            alias.clone(),
+            measurement.address.clone(),
+            measurement.timestamp.to_string(),
+            value(measurement.humidity),
+            value(measurement.temperature),
+            value(measurement.pressure),
+            value(measurement.battery_potential),
+            value(measurement.acceleration_x),
+            value(measurement.acceleration_y),
+            value(measurement.acceleration_z),
+            value(measurement.tx_power),
+            value(measurement.movement_counter),
+            value(measurement.measurement_sequence_number),
+            value(measurement.rssi),
+            value(measurement.data_format),
+            metadata.join(";"),
+        ];
+
+        let row: Vec<String> = row.iter().map(|field| escape_csv(field)).collect();
+
+        rows.push_str(&row.join(","));
+        rows.push('\n');
+    }
+
+    rows
+}
+
+/// Quotes a CSV field if needed.
+fn escape_csv(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Escapes a tag key, tag value or field key for the line protocol.
+fn escape_influx(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kitchen_measurements;
+
+    #[test]
+    fn test_render_influx() {
+        let mut measurements = kitchen_measurements();
+        let kitchen = measurements.get_mut("kitchen").unwrap();
+        kitchen.humidity = Some(40.25);
+        kitchen.movement_counter = Some(66);
+        kitchen.rssi = Some(-70);
+        kitchen.data_format = Some(5);
+        kitchen
+            .metadata
+            .insert("room".to_string(), "living room".to_string());
+
+        measurements.insert(
+            "attic".to_string(),
+            Measurement {
+                address: "BB:BB:BB:BB:BB:BB".to_string(),
+                timestamp: 1554300001,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(
+            Format::Influx.render(&measurements),
+            "ruuvitag,alias=kitchen,address=AA:AA:AA:AA:AA:AA,room=living\\ room \
+             humidity=40.25,temperature=21.5,movement_counter=66i,rssi=-70i,data_format=5i \
+             1554300000\n"
+        );
+    }
+
+    #[test]
+    fn test_render_csv() {
+        let mut measurements = kitchen_measurements();
+        let kitchen = measurements.get_mut("kitchen").unwrap();
+        kitchen.humidity = Some(40.25);
+        kitchen.rssi = Some(-70);
+        kitchen.data_format = Some(3);
+        kitchen
+            .metadata
+            .insert("room".to_string(), "kitchen, north".to_string());
+
+        measurements.insert(
+            "attic".to_string(),
+            Measurement {
+                address: "BB:BB:BB:BB:BB:BB".to_string(),
+                timestamp: 1554300001,
+                ..Default::default()
+            },
+        );
+
+        let header = "alias,address,timestamp,humidity,temperature,pressure,\
+                      battery_potential,acceleration_x,acceleration_y,acceleration_z,tx_power,\
+                      movement_counter,measurement_sequence_number,rssi,data_format,metadata\n";
+        let records = "attic,BB:BB:BB:BB:BB:BB,1554300001,,,,,,,,,,,,,\n\
+                       kitchen,AA:AA:AA:AA:AA:AA,1554300000,40.25,21.5,,,,,,,,,-70,3,\
+                       \"room=kitchen, north\"\n";
+
+        assert_eq!(Format::Csv.header().as_deref(), Some(header));
+        assert_eq!(Format::Csv.render_records(&measurements), records);
+        assert_eq!(
+            Format::Csv.render(&measurements),
+            format!("{}{}", header, records)
+        );
+    }
+
+    #[test]
+    fn test_render_batch() {
+        let first = kitchen_measurements();
+        let mut second = first.clone();
+        second.get_mut("kitchen").unwrap().timestamp = 1554300060;
+
+        let batch = vec![first, second];
+
+        let json: serde_json::Value =
+            serde_json::from_str(&Format::Json.render_batch(&batch)).unwrap();
+        assert_eq!(json[0]["kitchen"]["timestamp"], 1554300000);
+        assert_eq!(json[1]["kitchen"]["timestamp"], 1554300060);
+
+        let csv = Format::Csv.render_batch(&batch);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("alias,"));
+        assert!(lines[2].starts_with("kitchen,AA:AA:AA:AA:AA:AA,1554300060,"));
+
+        assert_eq!(Format::Influx.render_batch(&batch).lines().count(), 2);
+    }
+
+    #[test]
+    fn test_upload_url() {
+        assert_eq!(
+            Format::Influx
+                .upload_url("http://localhost:8086/write?db=ruuvi")
+                .unwrap()
+                .as_str(),
+            "http://localhost:8086/write?db=ruuvi&precision=s"
+        );
+        assert_eq!(
+            Format::Influx
+                .upload_url("http://localhost:8086/write?db=ruuvi&precision=s")
+                .unwrap()
+                .as_str(),
+            "http://localhost:8086/write?db=ruuvi&precision=s"
+        );
+        assert_eq!(
+            Format::Json
+                .upload_url("http://localhost:8080/measurements")
+                .unwrap()
+                .as_str(),
+            "http://localhost:8080/measurements"
+        );
+    }
+}
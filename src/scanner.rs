@@ -0,0 +1,315 @@
+use std::io::BufRead;
+use std::sync::{
+    mpsc::{channel, Receiver},
+    Arc,
+};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rumble::api::{BDAddr, Central, CentralEvent, Peripheral};
+use rumble::bluez::adapter::ConnectedAdapter;
+
+use failure::Error;
+
+/// A single BLE advertisement as seen by a scanner.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Advertisement {
+    /// Address of the advertiser, formatted as XX:XX:XX:XX:XX:XX.
+    pub address: String,
+    /// Manufacturer specific data, including the two byte manufacturer id.
+    pub manufacturer_data: Vec<u8>,
+    /// Received signal strength, dBm.
+    pub rssi: Option<i8>,
+    /// Transmitter power from the TX power level field of the advertisement, dBm.
+    pub tx_power_level: Option<i8>,
+    /// Unix timestamp of when the advertisement was received.
+    pub timestamp: u64,
+}
+
+/// A source of advertisements.
+pub trait Scanner {
+    /// Starts scanning. The advertisements are delivered through the returned channel. The
+    /// channel is closed if the scanner runs out of advertisements.
+    fn start(&mut self) -> Result<Receiver<Advertisement>, Error>;
+
+    /// Stops scanning.
+    fn stop(&mut self) -> Result<(), Error>;
+}
+
+/// Returns the current time as a Unix timestamp.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Scans for advertisements using a BlueZ adapter.
+pub struct BluezScanner {
+    central: Arc<ConnectedAdapter>,
+}
+
+impl BluezScanner {
+    /// Connects to the adapter with the given name (e.g. hci1) or address. If no adapter is
+    /// given, the first adapter of the system is used. If `reset` is true, the adapter is
+    /// brought down and up again before connecting to it.
+    pub fn new(adapter: Option<&str>, reset: bool) -> Result<BluezScanner, Error> {
+        let manager = rumble::bluez::manager::Manager::new()?;
+
+        let adapters = manager.adapters()?;
+
+        let mut adapter = match adapter {
+            Some(wanted) => adapters
+                .into_iter()
+                .find(|adapter| {
+                    adapter.name == wanted
+                        || format!("{}", adapter.addr).eq_ignore_ascii_case(wanted)
+                })
+                .ok_or_else(|| failure::format_err!("bluetooth adapter {} not found", wanted))?,
+            None => adapters
+                .into_iter()
+                .next()
+                .ok_or_else(|| failure::format_err!("no bluetooth adapters found"))?,
+        };
+
+        if reset {
+            adapter = manager.down(&adapter)?;
+            adapter = manager.up(&adapter)?;
+        } else if !adapter.is_up() {
+            adapter = manager.up(&adapter)?;
+        }
+
+        Ok(BluezScanner {
+            central: Arc::new(adapter.connect()?),
+        })
+    }
+}
+
+impl Scanner for BluezScanner {
+    fn start(&mut self) -> Result<Receiver<Advertisement>, Error> {
+        let central_clone = self.central.clone();
+
+        let (adv_tx, adv_rx) = channel();
+
+        self.central.on_event(Box::new(move |event| {
+            if let Some(advertisement) = on_event(&central_clone, event) {
+                let _ = adv_tx.send(advertisement);
+            }
+        }));
+
+        self.central.start_scan()?;
+
+        Ok(adv_rx)
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        self.central.stop_scan()?;
+        Ok(())
+    }
+}
+
+fn on_event(central: &ConnectedAdapter, event: CentralEvent) -> Option<Advertisement> {
+    match event {
+        CentralEvent::DeviceDiscovered(addr) => on_event_with_address(central, addr),
+        CentralEvent::DeviceUpdated(addr) => on_event_with_address(central, addr),
+        _ => None,
+    }
+}
+
+fn on_event_with_address(central: &ConnectedAdapter, address: BDAddr) -> Option<Advertisement> {
+    match central.peripheral(address) {
+        Some(peripheral) => to_advertisement(address, peripheral),
+        None => None,
+    }
+}
+
+fn to_advertisement<T: Peripheral>(address: BDAddr, peripheral: T) -> Option<Advertisement> {
+    let properties = peripheral.properties();
+    let tx_power_level = properties.tx_power_level;
+    properties.manufacturer_data.map(|data| Advertisement {
+        address: format!("{}", address),
+        manufacturer_data: data,
+        // rumble 0.3 parses the HCI LE advertising reports itself and drops the RSSI byte at
+        // their end, so it is not available for live scans.
+        rssi: None,
+        tx_power_level,
+        timestamp: unix_timestamp(),
+    })
+}
+
+/// Replays a fixed set of advertisements. Useful for testing without Bluetooth hardware.
+#[cfg(test)]
+pub struct MemoryScanner {
+    advertisements: Vec<Advertisement>,
+}
+
+#[cfg(test)]
+impl MemoryScanner {
+    pub fn new(advertisements: Vec<Advertisement>) -> MemoryScanner {
+        MemoryScanner { advertisements }
+    }
+}
+
+#[cfg(test)]
+impl Scanner for MemoryScanner {
+    fn start(&mut self) -> Result<Receiver<Advertisement>, Error> {
+        let (adv_tx, adv_rx) = channel();
+
+        for advertisement in self.advertisements.drain(..) {
+            adv_tx.send(advertisement)?;
+        }
+
+        Ok(adv_rx)
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Reads advertisements from lines of text. Each line contains the address of the advertiser,
+/// the manufacturer specific data as hex and optionally a Unix timestamp and an RSSI, separated
+/// by whitespace. Empty lines and lines starting with `#` are skipped.
+pub struct ReplayScanner {
+    reader: Option<Box<dyn BufRead + Send>>,
+}
+
+impl ReplayScanner {
+    pub fn new<R: BufRead + Send + 'static>(reader: R) -> ReplayScanner {
+        ReplayScanner {
+            reader: Some(Box::new(reader)),
+        }
+    }
+}
+
+impl Scanner for ReplayScanner {
+    fn start(&mut self) -> Result<Receiver<Advertisement>, Error> {
+        let reader = match self.reader.take() {
+            Some(reader) => reader,
+            None => return Err(failure::format_err!("advertisements already replayed")),
+        };
+
+        let (adv_tx, adv_rx) = channel();
+
+        thread::spawn(move || {
+            for (index, line) in reader.lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(error) => {
+                        eprintln!("error: failed to read advertisements: {}", error);
+                        break;
+                    }
+                };
+
+                let line = line.trim();
+
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                match parse_advertisement(line) {
+                    Ok(advertisement) => {
+                        if adv_tx.send(advertisement).is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => eprintln!("warning: line {}: {}", index + 1, error),
+                }
+            }
+        });
+
+        Ok(adv_rx)
+    }
+
+    fn stop(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Parses a line of the form `address manufacturer-data-hex [timestamp] [rssi]`.
+pub fn parse_advertisement(line: &str) -> Result<Advertisement, Error> {
+    let mut fields = line.split_whitespace();
+
+    let address = match fields.next() {
+        Some(address) => address.to_uppercase(),
+        None => return Err(failure::format_err!("missing address")),
+    };
+
+    let manufacturer_data = match fields.next() {
+        Some(data) => parse_hex(data)?,
+        None => return Err(failure::format_err!("missing manufacturer data")),
+    };
+
+    let timestamp = match fields.next() {
+        Some(timestamp) => timestamp
+            .parse()
+            .map_err(|_| failure::format_err!("invalid timestamp: {}", timestamp))?,
+        None => unix_timestamp(),
+    };
+
+    let rssi = match fields.next() {
+        Some(rssi) => Some(
+            rssi.parse()
+                .map_err(|_| failure::format_err!("invalid rssi: {}", rssi))?,
+        ),
+        None => None,
+    };
+
+    if fields.next().is_some() {
+        return Err(failure::format_err!("too many fields"));
+    }
+
+    Ok(Advertisement {
+        address,
+        manufacturer_data,
+        rssi,
+        tx_power_level: None,
+        timestamp,
+    })
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>, Error> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(failure::format_err!("invalid manufacturer data: {}", s));
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| failure::format_err!("invalid manufacturer data: {}", s))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_advertisement() {
+        let advertisement =
+            parse_advertisement("aa:bb:cc:dd:ee:ff 990403170145 1554300000 -75").unwrap();
+
+        assert_eq!(
+            advertisement,
+            Advertisement {
+                address: "AA:BB:CC:DD:EE:FF".to_string(),
+                manufacturer_data: vec![0x99, 0x04, 0x03, 0x17, 0x01, 0x45],
+                rssi: Some(-75),
+                tx_power_level: None,
+                timestamp: 1554300000,
+            }
+        );
+
+        let advertisement = parse_advertisement("AA:BB:CC:DD:EE:FF 9904").unwrap();
+        assert_eq!(advertisement.manufacturer_data, vec![0x99, 0x04]);
+        assert_eq!(advertisement.rssi, None);
+
+        assert!(parse_advertisement("AA:BB:CC:DD:EE:FF").is_err());
+        assert!(parse_advertisement("AA:BB:CC:DD:EE:FF 990").is_err());
+        assert!(parse_advertisement("AA:BB:CC:DD:EE:FF 99xx").is_err());
+        assert!(parse_advertisement("AA:BB:CC:DD:EE:FF 9904 now").is_err());
+        assert!(parse_advertisement("AA:BB:CC:DD:EE:FF 9904 1 -75 x").is_err());
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+
+use hmac::{Hmac, Mac};
+
+use sha2::Sha256;
+
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::X509;
+
+use rand::Rng;
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+
+use crate::config::UploadConfig;
+use crate::output::Format;
+use crate::scanner::unix_timestamp;
+use crate::Measurement;
+
+/// Header the signature is sent in if none is configured.
+const DEFAULT_SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Header the timestamp of the signature is sent in if none is configured.
+const DEFAULT_TIMESTAMP_HEADER: &str = "X-Signature-Timestamp";
+
+/// Connect timeout if none is configured, seconds.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+
+/// Request timeout if none is configured, seconds.
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30;
+
+/// Number of retries if none is configured.
+const DEFAULT_RETRIES: u32 = 3;
+
+/// Delay before the first retry if none is configured, seconds.
+const DEFAULT_RETRY_DELAY: u64 = 1;
+
+/// Upper limit of the exponential backoff.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// The longest Retry-After that is waited for. If the server asks to wait longer, the upload
+/// fails right away.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Authorization sent with the uploads.
+enum Auth {
+    Bearer(String),
+    Basic(String, Option<String>),
+}
+
+/// Signs the uploads with HMAC-SHA256.
+struct Signing {
+    key: Vec<u8>,
+    signature_header: HeaderName,
+    timestamp_header: HeaderName,
+}
+
+/// Uploads sets of measurements to a URL with HTTP POST.
+pub struct Uploader {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    format: Format,
+    auth: Option<Auth>,
+    /// Extra headers sent with every upload.
+    headers: HeaderMap,
+    signing: Option<Signing>,
+    /// How many times a failed upload is retried.
+    retries: u32,
+    /// Delay before the first retry.
+    retry_delay: Duration,
+}
+
+impl Uploader {
+    /// Creates an uploader for the URL. The secrets of the configuration are read here, so that
+    /// missing ones are noticed before scanning.
+    pub fn new(url: &str, format: Format, config: &UploadConfig) -> Result<Uploader, Error> {
+        let auth = match (&config.bearer_token, &config.username) {
+            (Some(_), Some(_)) => {
+                return Err(failure::format_err!(
+                    "both a bearer token and a username given, only one can be used"
+                ));
+            }
+            (Some(token), None) => Some(Auth::Bearer(token.read()?)),
+            (None, Some(username)) => {
+                let password = match config.password {
+                    Some(ref password) => Some(password.read()?),
+                    None => None,
+                };
+                Some(Auth::Basic(username.clone(), password))
+            }
+            (None, None) if config.password.is_some() => {
+                return Err(failure::format_err!("password given without a username"));
+            }
+            (None, None) => None,
+        };
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &config.headers {
+            let name = header_name(name)?;
+            let value = HeaderValue::from_str(value)
+                .map_err(|error| failure::format_err!("invalid header {}: {}", name, error))?;
+            headers.insert(name, value);
+        }
+
+        let signing = match config.signing {
+            Some(ref signing) => Some(Signing {
+                key: signing.key.read()?.into_bytes(),
+                signature_header: header_name(
+                    signing
+                        .signature_header
+                        .as_deref()
+                        .unwrap_or(DEFAULT_SIGNATURE_HEADER),
+                )?,
+                timestamp_header: header_name(
+                    signing
+                        .timestamp_header
+                        .as_deref()
+                        .unwrap_or(DEFAULT_TIMESTAMP_HEADER),
+                )?,
+            }),
+            None => None,
+        };
+
+        Ok(Uploader {
+            client: build_client(config)?,
+            url: format.upload_url(url)?,
+            format,
+            auth,
+            headers,
+            signing,
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            retry_delay: Duration::from_secs(config.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY)),
+        })
+    }
+
+    /// Uploads a set of measurements rendered in the format of the uploader.
+    pub fn upload(&self, measurements: &HashMap<String, Measurement>) -> Result<(), Error> {
+        self.post(&self.format.render(measurements))
+    }
+
+    /// Uploads several sets of measurements in a single request.
+    pub fn upload_batch(&self, batch: &[HashMap<String, Measurement>]) -> Result<(), Error> {
+        self.post(&self.format.render_batch(batch))
+    }
+
+    /// Posts the body to the URL. Connection errors, timeouts, server errors and 429 Too Many
+    /// Requests are retried with exponential backoff.
+    fn post(&self, body: &str) -> Result<(), Error> {
+        let mut attempt = 0;
+
+        loop {
+            // The request is built again for each attempt, so that the signature is fresh.
+            let request = self.request(body)?;
+
+            let (error, retry_after) = match self.client.execute(request) {
+                Ok(response) => match response.error_for_status_ref() {
+                    Ok(_) => return Ok(()),
+                    Err(error) => {
+                        let status = response.status();
+                        if !status.is_server_error() && status != StatusCode::TOO_MANY_REQUESTS {
+                            return Err(error.into());
+                        }
+                        let retry_after = response
+                            .headers()
+                            .get(RETRY_AFTER)
+                            .and_then(parse_retry_after);
+                        (error, retry_after)
+                    }
+                },
+                Err(error) if !error.is_http() && !error.is_timeout() => {
+                    return Err(error.into());
+                }
+                Err(error) => (error, None),
+            };
+
+            if attempt >= self.retries {
+                return Err(error.into());
+            }
+
+            let delay = match retry_after {
+                Some(delay) if delay > MAX_RETRY_AFTER => return Err(error.into()),
+                Some(delay) => delay,
+                None => backoff(self.retry_delay, attempt),
+            };
+
+            eprintln!(
+                "warning: {}, retrying in {:.1} seconds",
+                error,
+                delay.as_secs_f64()
+            );
+
+            thread::sleep(delay);
+
+            attempt += 1;
+        }
+    }
+
+    fn request(&self, body: &str) -> Result<reqwest::Request, Error> {
+        let mut builder = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, self.format.content_type())
+            .headers(self.headers.clone());
+
+        builder = match self.auth {
+            Some(Auth::Bearer(ref token)) => builder.bearer_auth(token),
+            Some(Auth::Basic(ref username, ref password)) => {
+                builder.basic_auth(username, password.as_ref())
+            }
+            None => builder,
+        };
+
+        if let Some(ref signing) = self.signing {
+            let timestamp = unix_timestamp();
+            builder = builder
+                .header(signing.timestamp_header.clone(), timestamp)
+                .header(
+                    signing.signature_header.clone(),
+                    sign(&signing.key, timestamp, body),
+                );
+        }
+
+        Ok(builder.body(body.to_string()).build()?)
+    }
+}
+
+/// Returns the delay before a retry: the initial delay doubled for each earlier retry, up to a
+/// limit, and randomized to between half and all of that so that clients don't retry in sync.
+fn backoff(initial: Duration, attempt: u32) -> Duration {
+    let delay = initial
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY));
+    delay.mul_f64(rand::thread_rng().gen_range(0.5, 1.0))
+}
+
+/// Parses a Retry-After header given in seconds. HTTP dates are not supported.
+fn parse_retry_after(value: &HeaderValue) -> Option<Duration> {
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Builds the HTTP client used for uploading, with the timeouts and TLS settings of the
+/// configuration.
+fn build_client(config: &UploadConfig) -> Result<reqwest::Client, Error> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(
+            config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        ))
+        .timeout(Duration::from_secs(
+            config.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+        ));
+
+    let config = &config.tls;
+
+    if let Some(ref path) = config.ca_file {
+        for certificate in read_certificates(path)? {
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_der(&certificate.to_der()?)?);
+        }
+    }
+
+    match (&config.cert_file, &config.key_file) {
+        (Some(cert_file), key_file) => {
+            let key_file = key_file.as_ref().unwrap_or(cert_file);
+            builder = builder.identity(read_identity(cert_file, key_file)?);
+        }
+        (None, Some(_)) => {
+            return Err(failure::format_err!(
+                "TLS client key given without a certificate"
+            ));
+        }
+        (None, None) => {}
+    }
+
+    if config.danger_accept_invalid_certs.unwrap_or(false) {
+        eprintln!("warning: TLS certificates are not verified when uploading");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    Ok(builder.build()?)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path)
+        .map_err(|error| failure::format_err!("failed to read {}: {}", path.display(), error))
+}
+
+/// Reads the PEM certificates of a file, at least one.
+fn read_certificates(path: &Path) -> Result<Vec<X509>, Error> {
+    let certificates = X509::stack_from_pem(&read_file(path)?)
+        .map_err(|error| failure::format_err!("failed to parse {}: {}", path.display(), error))?;
+
+    if certificates.is_empty() {
+        return Err(failure::format_err!(
+            "no certificates in {}",
+            path.display()
+        ));
+    }
+
+    Ok(certificates)
+}
+
+/// Reads a client certificate, its chain and its private key from PEM files. The native TLS
+/// backend of reqwest only accepts PKCS #12 identities, so the certificate and the key are
+/// converted first.
+fn read_identity(cert_file: &Path, key_file: &Path) -> Result<reqwest::Identity, Error> {
+    let mut certificates = read_certificates(cert_file)?;
+    let certificate = certificates.remove(0);
+
+    let mut chain = Stack::new()?;
+    for certificate in certificates {
+        chain.push(certificate)?;
+    }
+
+    let key = PKey::private_key_from_pem(&read_file(key_file)?).map_err(|error| {
+        failure::format_err!("failed to parse {}: {}", key_file.display(), error)
+    })?;
+
+    let pkcs12 = Pkcs12::builder()
+        .name("ruuvitag-upload")
+        .pkey(&key)
+        .cert(&certificate)
+        .ca(chain)
+        .build2("")?;
+
+    Ok(reqwest::Identity::from_pkcs12_der(&pkcs12.to_der()?, "")?)
+}
+
+fn header_name(name: &str) -> Result<HeaderName, Error> {
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|error| failure::format_err!("invalid header {}: {}", name, error))
+}
+
+/// Returns the HMAC-SHA256 of the timestamp and the body, separated by a period, as hex.
+fn sign(key: &[u8], timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, TlsConfig};
+    use std::env;
+
+    #[test]
+    fn test_request_headers() {
+        env::set_var("RUUVITAG_UPLOAD_TEST_TOKEN", "secret");
+
+        let config = Config::parse(
+            r#"
+            [upload]
+            bearer_token = { env = "RUUVITAG_UPLOAD_TEST_TOKEN" }
+
+            [upload.headers]
+            X-Gateway = "attic"
+            "#,
+        )
+        .unwrap();
+
+        let uploader = Uploader::new("http://localhost/", Format::Json, &config.upload).unwrap();
+        let request = uploader.request("{}").unwrap();
+
+        assert_eq!(request.headers()["authorization"], "Bearer secret");
+        assert_eq!(request.headers()["x-gateway"], "attic");
+        assert_eq!(request.headers()["content-type"], "application/json");
+
+        let config = Config::parse(
+            r#"
+            [upload]
+            username = "gateway"
+            password = { env = "RUUVITAG_UPLOAD_TEST_TOKEN" }
+            "#,
+        )
+        .unwrap();
+
+        let uploader = Uploader::new("http://localhost/", Format::Json, &config.upload).unwrap();
+        let request = uploader.request("{}").unwrap();
+
+        // gateway:secret
+        assert_eq!(
+            request.headers()["authorization"],
+            "Basic Z2F0ZXdheTpzZWNyZXQ="
+        );
+    }
+
+    #[test]
+    fn test_build_client() {
+        use openssl::asn1::Asn1Time;
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::x509::X509NameBuilder;
+
+        fn tls(config: TlsConfig) -> UploadConfig {
+            UploadConfig {
+                tls: config,
+                ..Default::default()
+            }
+        }
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "ruuvitag-upload").unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let certificate = builder.build();
+
+        let test_dir = assert_fs::TempDir::new().unwrap();
+        let cert_file = test_dir.path().join("client.pem");
+        let key_file = test_dir.path().join("client.key");
+        fs::write(&cert_file, certificate.to_pem().unwrap()).unwrap();
+        fs::write(&key_file, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+
+        let config = TlsConfig {
+            ca_file: Some(cert_file.clone()),
+            cert_file: Some(cert_file.clone()),
+            key_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+        assert!(build_client(&tls(config)).is_ok());
+
+        let config = TlsConfig {
+            ca_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+        assert!(build_client(&tls(config)).is_err());
+
+        let config = TlsConfig {
+            key_file: Some(key_file),
+            ..Default::default()
+        };
+        assert!(build_client(&tls(config)).is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let initial = Duration::from_secs(2);
+
+        for attempt in 0..3 {
+            let delay = backoff(initial, attempt);
+            let limit = initial * (1 << attempt);
+            assert!(delay >= limit / 2 && delay <= limit);
+        }
+
+        assert!(backoff(initial, 100) <= MAX_RETRY_DELAY);
+
+        // A 32-bit draw leaves the thread RNG's buffer at an odd offset, which made rand_core
+        // 0.4.0 read the next 64 bits through a misaligned pointer.
+        let _ = rand::random::<u32>();
+        let delay = backoff(initial, 0);
+        assert!(delay >= initial / 2 && delay <= initial);
+
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("120")),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after(&HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign(b"secret", 1554300000, "{\"kitchen\":{}}"),
+            "c936ed1ba97273440657e84d02fae0da89bf8c7637fc81ab280135d5db33a27a"
+        );
+    }
+}